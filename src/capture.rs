@@ -0,0 +1,257 @@
+//! Input capture and pulse measurement on the timer I/O pins.
+//!
+//! | Mode             | Counter                               | Reading              |
+//! |------------------|---------------------------------------|----------------------|
+//! | `Capture`        | Free running, wraps at 2^32           | Ticks between edges  |
+//! | `CaptureCompare` | Restarts from 1 on every edge         | Ticks between edges  |
+//! | `Gated`          | Only counts while the input is active | [`Capture::count`]   |
+//!
+//! Captures can be polled with [`Capture::read`] or pushed into a `heapless`
+//! queue from the timer interrupt with [`Capture::capture_into`]. In `Gated`
+//! mode nothing is latched, so the active ticks are only available through
+//! [`Capture::count`] and restarted with [`Capture::clear_count`]; `read`
+//! then only reports the counter reaching its compare value.
+use crate::clocks::{gate_peripheral, Clocks};
+use crate::gpio::{AltFn, AltMode, Floating, Input, Pin, AF3};
+use crate::pac::{TMR0, TMR1, TMR2};
+use crate::timer::{Hertz, Instance, Prescaler, TMR0_IO, TMR1_IO, TMR2_IO};
+use cortex_m::peripheral::NVIC;
+use heapless::spsc::Producer;
+
+/// Capture errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The counter reached its compare value before the next edge, the
+    /// signal is slower than the timer can measure with this prescaler.
+    Overflow,
+    /// The capture buffer was full and the capture was dropped.
+    BufferFull,
+}
+
+/// Timer input capture modes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// Count is latched on each edge while the counter keeps running.
+    Capture,
+    /// Count is latched and the counter restarted on each edge. Periods of
+    /// 2^32 ticks or more are not detected, pick a prescaler to suit.
+    CaptureCompare,
+    /// Counter only runs while the input is active.
+    Gated,
+}
+
+/// Active input edge or level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    /// Rising edge, or high level in gated mode.
+    Rising,
+    /// Falling edge, or low level in gated mode.
+    Falling,
+}
+
+pub struct Capture<TMR, AF: AltMode, const IDX: u8> {
+    tmr: TMR,
+    pin: Pin<AF, Input<Floating>, IDX>,
+    pclk: u32,
+    psc: Prescaler,
+    mode: CaptureMode,
+    last: u32,
+    rolled_over: bool,
+}
+
+impl<TMR: Instance, AF: AltMode, const IDX: u8> Capture<TMR, AF, IDX> {
+    fn configure(
+        tmr: TMR,
+        pin: Pin<AF, Input<Floating>, IDX>,
+        clocks: &Clocks,
+        mode: CaptureMode,
+        edge: Edge,
+    ) -> Self {
+        clocks.enable_peripheral::<TMR>();
+        clocks.reset_peripheral::<TMR>();
+        match mode {
+            CaptureMode::Capture => tmr.cn.modify(|_, w| w.tmode().capture()),
+            CaptureMode::CaptureCompare => tmr.cn.modify(|_, w| w.tmode().capture_compare()),
+            CaptureMode::Gated => tmr.cn.modify(|_, w| w.tmode().gated()),
+        }
+        match edge {
+            Edge::Rising => tmr.cn.modify(|_, w| w.tpol().active_hi()),
+            Edge::Falling => tmr.cn.modify(|_, w| w.tpol().active_lo()),
+        }
+        // Longest possible period before the counter rolls over
+        tmr.cmp.write(|w| unsafe { w.bits(u32::MAX) });
+        Self {
+            tmr,
+            pin,
+            pclk: clocks.pclk(),
+            psc: Prescaler::D1,
+            mode,
+            last: 1,
+            rolled_over: false,
+        }
+    }
+
+    /// Stops the timer, gates its clock and releases the timer and pin.
+    pub fn free(mut self) -> (TMR, Pin<AF, Input<Floating>, IDX>) {
+        self.stop();
+        self.unlisten();
+        gate_peripheral::<TMR>();
+        (self.tmr, self.pin)
+    }
+
+    /// Selects the count clock prescaler, trading resolution for range.
+    pub fn set_prescaler(&mut self, psc: Prescaler) {
+        self.psc = psc;
+        psc.write(&self.tmr);
+    }
+
+    /// Frequency the timer counts at (in Hz).
+    pub fn tick_freq(&self) -> u32 {
+        self.pclk >> self.psc.shift()
+    }
+
+    /// Converts a measured period in ticks into a frequency.
+    pub fn frequency(&self, ticks: u32) -> Hertz {
+        Hertz(self.tick_freq() / ticks.max(1))
+    }
+
+    /// Starts capturing from a count of 1.
+    pub fn start(&mut self) {
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+        self.tmr.cnt.write(|w| unsafe { w.bits(1) });
+        self.last = 1;
+        self.rolled_over = false;
+        self.clear_interrupt();
+        self.tmr.cn.modify(|_, w| w.ten().en());
+    }
+
+    /// Stops the timer.
+    pub fn stop(&mut self) {
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+    }
+
+    /// Ticks counted so far, e.g. active time in gated mode. Reads 0 before
+    /// [`Capture::start`], while the count still has its reset value of 0.
+    pub fn count(&self) -> u32 {
+        self.tmr.cnt.read().bits().saturating_sub(1)
+    }
+
+    /// Reads the next measurement.
+    ///
+    /// In `Capture` and `CaptureCompare` modes this is the number of ticks
+    /// since the previous edge; the first reading after [`Capture::start`] is
+    /// measured from the start. In `Gated` mode there are no measurements,
+    /// only [`Error::Overflow`] once the count reaches the compare value, use
+    /// [`Capture::count`] instead.
+    pub fn read(&mut self) -> nb::Result<u32, Error> {
+        if !self.pending_interrupt() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.clear_interrupt();
+        let captured = self.tmr.pwm.read().bits();
+        match self.mode {
+            CaptureMode::Capture => {
+                // A rollover raises the flag without latching a new count
+                if captured == self.last {
+                    let overflow = self.rolled_over;
+                    self.rolled_over = true;
+                    return if overflow {
+                        Err(nb::Error::Other(Error::Overflow))
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    };
+                }
+                // Wrapping subtraction covers a single rollover of the counter
+                let overflow = self.rolled_over && captured >= self.last;
+                let ticks = captured.wrapping_sub(self.last);
+                self.last = captured;
+                self.rolled_over = false;
+                if overflow {
+                    Err(nb::Error::Other(Error::Overflow))
+                } else {
+                    Ok(ticks)
+                }
+            }
+            // Equal periods latch equal counts, so unlike `Capture` a
+            // rollover can't be told apart from an edge here.
+            // A rollover before the first edge leaves the reset value of 0.
+            CaptureMode::CaptureCompare => match captured {
+                0 => Err(nb::Error::Other(Error::Overflow)),
+                _ => Ok(captured - 1),
+            },
+            CaptureMode::Gated => {
+                // The interrupt only fires in gated mode once the counter
+                // reaches the compare value.
+                Err(nb::Error::Other(Error::Overflow))
+            }
+        }
+    }
+
+    /// Restarts the gated count.
+    pub fn clear_count(&mut self) {
+        self.tmr.cnt.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Moves a pending capture into `buffer`, for use from the timer interrupt.
+    pub fn capture_into<const N: usize>(
+        &mut self,
+        buffer: &mut Producer<'_, u32, N>,
+    ) -> nb::Result<(), Error> {
+        let ticks = self.read()?;
+        buffer
+            .enqueue(ticks)
+            .map_err(|_| nb::Error::Other(Error::BufferFull))
+    }
+
+    /// Enables the capture interrupt in the NVIC.
+    pub fn listen(&mut self) {
+        unsafe { NVIC::unmask(TMR::INTERRUPT) };
+    }
+
+    /// Disables the capture interrupt in the NVIC.
+    pub fn unlisten(&mut self) {
+        NVIC::mask(TMR::INTERRUPT);
+    }
+
+    /// Check if a capture or overflow is pending.
+    pub fn pending_interrupt(&self) -> bool {
+        self.tmr.intr.read().irq_clr().bit_is_set()
+    }
+
+    /// Clear the capture interrupt flag.
+    pub fn clear_interrupt(&mut self) {
+        self.tmr.intr.write(|w| w.irq_clr().set_bit());
+    }
+}
+
+macro_rules! capture_pins {
+    (
+        [$(($new: ident, $TMR: ident, $AF: ident, $idx: expr),)+]
+    ) => {
+        $(
+            impl Capture<$TMR, $AF, $idx> {
+                /// Claims the timer input pin and configures the capture mode.
+                /// Capturing begins with [`Capture::start`].
+                pub fn $new<A: AltMode, IO>(
+                    tmr: $TMR,
+                    pin: Pin::<A, IO, $idx>,
+                    clocks: &Clocks,
+                    mode: CaptureMode,
+                    edge: Edge
+                ) -> Self
+                where
+                    Pin<$AF, IO, $idx>: AltFn,
+                {
+                    let pin = pin.into_mode::<$AF>().into_floating_input();
+                    Self::configure(tmr, pin, clocks, mode, edge)
+                }
+            }
+        )+
+    };
+}
+
+capture_pins!([
+    (tmr0, TMR0, AF3, TMR0_IO),
+    (tmr1, TMR1, AF3, TMR1_IO),
+    (tmr2, TMR2, AF3, TMR2_IO),
+]);
@@ -1,57 +1,131 @@
 //! Control system oscillators and clock sources
 //!
-use crate::pac::gcr::CLKCN;
+//! The system clock is configured once through [`ClockConfig`], which consumes
+//! the GCR and produces a frozen [`Clocks`] value. Peripheral constructors take
+//! `&Clocks` so that their timing calculations follow the clock tree that is
+//! actually running.
+//!
+//! Peripheral clock gating and resets (GCR PERCKCN0/1 and RSTR0/1) are also
+//! controlled through [`Clocks`]. Drivers un-gate and reset their block when
+//! constructed and gate it again when released.
+//!
+//! The MAX32660 has no HFIO trim register or hardware trim engine. Instead
+//! [`Clocks::calibrate_hfio`] measures the HFIO against the 32.768KHz crystal
+//! and the measured frequency is used for the frozen clock values.
+use crate::pac::gcr::{self, CLKCN};
+use crate::pac::pwrseq::lp_ctrl::OVR_A;
+use crate::pac::{
+    DMA, FLC, GCR, GPIO0, I2C0, I2C1, ICC0, PWRSEQ, RTC, SPI17Y, SPIMSS, TMR0, TMR1, TMR2, UART0,
+    UART1, WDT0,
+};
+use crate::rtc::Rtc;
+use cortex_m::interrupt;
+use cortex_m::peripheral::{DCB, DWT};
 
-/// High Frequency Clock Frequency (in Hz).
+/// High Frequency Internal Oscillator frequency at the reset OVR setting (in Hz).
 pub const HFCLK_FREQ: u32 = 96_000_000;
-pub const PCLK_FREQ: u32 = HFCLK_FREQ / 2;
 /// Low Frequency Clock Frequency (in Hz).
 pub const LFCLK_FREQ: u32 = 32_768;
+/// Nano-Ring Oscillator Frequency (in Hz).
+pub const NANO_FREQ: u32 = 8_000;
 
-trait Oscillator {
-    fn enable(&self, clkcn: &CLKCN) -> ();
-    fn disable(&self, clkcn: &CLKCN) -> ();
+/// Core cycles taken by a poll of a ready flag, at least.
+const POLL_CYCLES: u32 = 4;
+/// Time allowed for the 32.768KHz crystal to start up (in ms).
+const X32K_TIMEOUT_MS: u32 = 2000;
+/// Time allowed for the HFIO to settle or the system clock to switch (in ms).
+const READY_TIMEOUT_MS: u32 = 10;
+
+/// Clock configuration errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The 32.768KHz crystal did not report ready, e.g. no crystal fitted.
+    X32kNotReady,
+    /// The HFIO did not report ready.
+    HfioNotReady,
+    /// The system clock did not switch over to the selected source.
+    SwitchTimeout,
+    /// HFIO calibration requires the HFIO to drive the system clock.
+    NotRunningFromHfio,
+    /// HFIO calibration requires the RTC to be counting.
+    RtcNotRunning,
+}
+
+/// Polls `ready` until it returns true or at least `timeout_ms` have passed
+/// at a system clock of `sysclk`.
+fn wait_for(
+    sysclk: u32,
+    timeout_ms: u32,
+    mut ready: impl FnMut() -> bool,
+    error: Error,
+) -> Result<(), Error> {
+    let polls = u64::from(sysclk) * u64::from(timeout_ms) / 1000 / u64::from(POLL_CYCLES);
+    for _ in 0..polls.max(1) {
+        if ready() {
+            return Ok(());
+        }
+    }
+    Err(error)
 }
 
+trait Oscillator {
+    fn enable(&self, clkcn: &CLKCN);
+    fn disable(&self, clkcn: &CLKCN);
+    fn is_ready(&self, clkcn: &CLKCN) -> bool;
+    fn not_ready(&self) -> Error;
+    /// Start-up time allowed (in ms).
+    fn timeout_ms(&self) -> u32;
+}
 
 /// 32.768KHz External Crystal or Clock X32K
 struct X32k;
-/// 80KHz Nano-Ring oscillator which cannot be disabled.
-struct NanoRing;
 /// High-Frequency Internal Oscillator
 struct Hfio;
 
 impl Oscillator for X32k {
-    fn enable(&self, clkcn: &CLKCN) -> () {
-        clkcn.write(|w| w.x32k_en().en());
+    fn enable(&self, clkcn: &CLKCN) {
+        clkcn.modify(|_, w| w.x32k_en().en());
+    }
+    fn disable(&self, clkcn: &CLKCN) {
+        clkcn.modify(|_, w| w.x32k_en().dis());
     }
-    fn disable(&self, clkcn: &CLKCN) -> () {
-        clkcn.write(|w| w.x32k_en().dis());
+    fn is_ready(&self, clkcn: &CLKCN) -> bool {
+        clkcn.read().x32k_rdy().is_ready()
+    }
+    fn not_ready(&self) -> Error {
+        Error::X32kNotReady
+    }
+    fn timeout_ms(&self) -> u32 {
+        X32K_TIMEOUT_MS
     }
 }
 
 impl Oscillator for Hfio {
-    fn enable(&self, clkcn: &CLKCN) -> () {
-        clkcn.write(|w| w.hirc_en().en());
+    fn enable(&self, clkcn: &CLKCN) {
+        clkcn.modify(|_, w| w.hirc_en().en());
     }
-    fn disable(&self, clkcn: &CLKCN) -> () {
-        clkcn.write(|w| w.hirc_en().en());
+    fn disable(&self, clkcn: &CLKCN) {
+        clkcn.modify(|_, w| w.hirc_en().dis());
+    }
+    fn is_ready(&self, clkcn: &CLKCN) -> bool {
+        clkcn.read().hirc_rdy().is_ready()
+    }
+    fn not_ready(&self) -> Error {
+        Error::HfioNotReady
+    }
+    fn timeout_ms(&self) -> u32 {
+        READY_TIMEOUT_MS
     }
 }
 
-pub struct Clocks<'a>{
-    x32k: X32k,
-    nano: NanoRing,
-    hfio: Hfio,
-    clkcn: &'a CLKCN,
-}
-
-pub enum ClkSrc{
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClkSrc {
     Hfio,
     Nano,
     X32k,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Div {
     D1,
     D2,
@@ -60,52 +134,485 @@ pub enum Div {
     D16,
     D32,
     D64,
-    D128
+    D128,
 }
 
-// Before setting OVR and thus changing the SysClk rate you need to select Nano or X32K for SysClk
-impl<'a> Clocks<'a>{
-    pub fn new(clkcn: &'a CLKCN) -> Self{
-        Self{x32k: X32k, nano: NanoRing, hfio: Hfio, clkcn}
+impl Div {
+    /// Right shift applied to the source frequency by the prescaler.
+    fn shift(self) -> u32 {
+        match self {
+            Div::D1 => 0,
+            Div::D2 => 1,
+            Div::D4 => 2,
+            Div::D8 => 3,
+            Div::D16 => 4,
+            Div::D32 => 5,
+            Div::D64 => 6,
+            Div::D128 => 7,
+        }
     }
+}
 
-    pub fn enable(&self, clk_src: ClkSrc){
-        match clk_src {
-            ClkSrc::Hfio => self.hfio.enable(&self.clkcn),
-            ClkSrc::Nano => (),
-            ClkSrc::X32k => self.x32k.enable(&self.clkcn)
+/// Operating voltage range of the core supply.
+///
+/// The HFIO frequency scales with the selected range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ovr {
+    /// 0.9V, HFIO runs at 24MHz.
+    V0_9,
+    /// 1.0V, HFIO runs at 48MHz.
+    V1_0,
+    /// 1.1V, HFIO runs at 96MHz (reset value).
+    V1_1,
+}
+
+impl Ovr {
+    /// HFIO frequency for this operating voltage range (in Hz).
+    pub fn hfio_freq(self) -> u32 {
+        match self {
+            Ovr::V0_9 => 24_000_000,
+            Ovr::V1_0 => 48_000_000,
+            Ovr::V1_1 => HFCLK_FREQ,
+        }
+    }
+
+    fn read(pwrseq: &crate::pac::pwrseq::RegisterBlock) -> Self {
+        match pwrseq.lp_ctrl.read().ovr().variant() {
+            Some(OVR_A::_0_9V) => Ovr::V0_9,
+            Some(OVR_A::_1_0V) => Ovr::V1_0,
+            _ => Ovr::V1_1,
+        }
+    }
+}
+
+/// System clock configuration builder.
+///
+/// Defaults to the reset configuration: HFIO undivided.
+pub struct ClockConfig {
+    gcr: GCR,
+    sys_src: ClkSrc,
+    psc: Div,
+}
+
+impl ClockConfig {
+    pub fn new(gcr: GCR) -> Self {
+        Self {
+            gcr,
+            sys_src: ClkSrc::Hfio,
+            psc: Div::D1,
+        }
+    }
+
+    /// Selects the oscillator used as the system clock source.
+    pub fn sys_osc_source(mut self, clk_src: ClkSrc) -> Self {
+        self.sys_src = clk_src;
+        self
+    }
+
+    /// Selects the system clock prescaler.
+    pub fn sys_osc_prescaler(mut self, psc_div: Div) -> Self {
+        self.psc = psc_div;
+        self
+    }
+
+    /// Applies the configuration and returns the frozen clock frequencies.
+    ///
+    /// If the selected oscillator does not become ready the system clock is
+    /// left on its previous source and the configuration is handed back with
+    /// the error, so that firmware can fall back to another oscillator:
+    ///
+    /// ```ignore
+    /// let clocks = match ClockConfig::new(gcr).sys_osc_source(ClkSrc::X32k).freeze() {
+    ///     Ok(clocks) => clocks,
+    ///     Err((config, _)) => config.sys_osc_source(ClkSrc::Hfio).freeze().unwrap(),
+    /// };
+    /// ```
+    pub fn freeze(self) -> Result<Clocks, (ClockConfig, Error)> {
+        // OVR may have been changed by a bootloader, only read it here.
+        let ovr = Ovr::read(unsafe { &*PWRSEQ::ptr() });
+        let mut clocks = Clocks {
+            gcr: self.gcr,
+            ovr,
+            hfio_cal: None,
+            sys_src: self.sys_src,
+            psc: self.psc,
+            sysclk: 0,
+            pclk: 0,
+        };
+        let switched = clocks
+            .enable(self.sys_src)
+            .and_then(|_| clocks.set_sys_osc_source(self.sys_src));
+        if let Err(e) = switched {
+            let config = ClockConfig {
+                gcr: clocks.gcr,
+                sys_src: self.sys_src,
+                psc: self.psc,
+            };
+            return Err((config, e));
         }
+        clocks.set_sys_osc_prescaler(self.psc);
+        clocks.update_freqs();
+        Ok(clocks)
+    }
+}
+
+/// Frozen clock configuration.
+///
+/// Holds the frequencies the clock tree was configured for, which peripheral
+/// drivers use to derive their own timings.
+pub struct Clocks {
+    gcr: GCR,
+    ovr: Ovr,
+    hfio_cal: Option<u32>,
+    sys_src: ClkSrc,
+    psc: Div,
+    sysclk: u32,
+    pclk: u32,
+}
+
+impl Clocks {
+    fn clkcn(&self) -> &CLKCN {
+        &self.gcr.clkcn
     }
 
-    pub fn disable(&self, clk_src: ClkSrc){
+    /// Source oscillator frequency for the given clock source (in Hz).
+    fn source_freq(&self, clk_src: ClkSrc) -> u32 {
         match clk_src {
-            ClkSrc::Hfio => self.hfio.disable(&self.clkcn),
-            ClkSrc::Nano => (),
-            ClkSrc::X32k => self.x32k.disable(&self.clkcn)
+            ClkSrc::Hfio => self.hfio(),
+            ClkSrc::Nano => NANO_FREQ,
+            ClkSrc::X32k => LFCLK_FREQ,
         }
     }
 
-    pub fn set_sys_osc_source(&self, clk_src: ClkSrc) -> () {
+    /// System clock frequency the core is running at right now (in Hz), read
+    /// back from the hardware, as the frozen value lags behind switchovers.
+    fn running_sysclk(&self) -> u32 {
+        let clkcn = self.clkcn().read();
+        let src = if clkcn.clksel().is_nano_ring() {
+            ClkSrc::Nano
+        } else if clkcn.clksel().is_hfx_in() {
+            ClkSrc::X32k
+        } else {
+            ClkSrc::Hfio
+        };
+        self.source_freq(src) >> clkcn.psc().bits()
+    }
+
+    fn update_freqs(&mut self) {
+        self.sysclk = self.source_freq(self.sys_src) >> self.psc.shift();
+        // Peripheral clock is always half of the system clock.
+        self.pclk = self.sysclk / 2;
+    }
+
+    /// Selects the system clock source, restoring the previous selection if
+    /// the switchover does not complete.
+    fn set_sys_osc_source(&self, clk_src: ClkSrc) -> Result<(), Error> {
+        let sysclk = self.running_sysclk();
+        let previous = self.clkcn().read().clksel().bits();
         match clk_src {
-            ClkSrc::Hfio => self.clkcn.write(|w| w.clksel().hirc()),
-            ClkSrc::Nano => self.clkcn.write(|w| w.clksel().nano_ring()),
-            ClkSrc::X32k => self.clkcn.write(|w| w.clksel().hfx_in())
+            ClkSrc::Hfio => self.clkcn().modify(|_, w| w.clksel().hirc()),
+            ClkSrc::Nano => self.clkcn().modify(|_, w| w.clksel().nano_ring()),
+            ClkSrc::X32k => self.clkcn().modify(|_, w| w.clksel().hfx_in()),
+        }
+
+        // Wait for clock selection to finish, which runs from the previous
+        // source until the switch completes
+        let switched = wait_for(
+            sysclk,
+            READY_TIMEOUT_MS,
+            || self.clkcn().read().ckrdy().is_ready(),
+            Error::SwitchTimeout,
+        );
+        if switched.is_err() {
+            self.clkcn()
+                .modify(|_, w| unsafe { w.clksel().bits(previous) });
         }
-        
-        // Wait for clock selection to finish
-        while self.clkcn.read().ckrdy().is_busy() {}
+        switched
     }
 
-    pub fn set_sys_osc_prescaler(&self, psc_div: Div) {
+    fn set_sys_osc_prescaler(&self, psc_div: Div) {
         match psc_div {
-            Div::D1 => self.clkcn.write(|w| w.psc().div1()),
-            Div::D2 => self.clkcn.write(|w| w.psc().div2()),
-            Div::D4 => self.clkcn.write(|w| w.psc().div4()),
-            Div::D8 => self.clkcn.write(|w| w.psc().div8()),
-            Div::D16 => self.clkcn.write(|w| w.psc().div16()),
-            Div::D32 => self.clkcn.write(|w| w.psc().div32()),
-            Div::D64 => self.clkcn.write(|w| w.psc().div64()),
-            Div::D128 => self.clkcn.write(|w| w.psc().div128()),
+            Div::D1 => self.clkcn().modify(|_, w| w.psc().div1()),
+            Div::D2 => self.clkcn().modify(|_, w| w.psc().div2()),
+            Div::D4 => self.clkcn().modify(|_, w| w.psc().div4()),
+            Div::D8 => self.clkcn().modify(|_, w| w.psc().div8()),
+            Div::D16 => self.clkcn().modify(|_, w| w.psc().div16()),
+            Div::D32 => self.clkcn().modify(|_, w| w.psc().div32()),
+            Div::D64 => self.clkcn().modify(|_, w| w.psc().div64()),
+            Div::D128 => self.clkcn().modify(|_, w| w.psc().div128()),
+        }
+    }
+
+    /// Changes the operating voltage range, retuning the HFIO to 24, 48 or 96MHz.
+    ///
+    /// Before setting OVR and thus changing the SysClk rate the Nano-Ring is
+    /// selected for SysClk. Once the HFIO has settled the original source is
+    /// selected again and the frozen frequencies are updated. Peripherals that
+    /// were configured from the previous frequencies must be reconfigured.
+    ///
+    /// If the HFIO does not settle the system clock is left on the Nano-Ring
+    /// and the frozen frequencies reflect that.
+    pub fn set_ovr(&mut self, pwrseq: &PWRSEQ, ovr: Ovr) -> Result<(), Error> {
+        if ovr == self.ovr {
+            return Ok(());
+        }
+        self.set_sys_osc_source(ClkSrc::Nano)?;
+
+        match ovr {
+            Ovr::V0_9 => pwrseq.lp_ctrl.modify(|_, w| w.ovr()._0_9v()),
+            Ovr::V1_0 => pwrseq.lp_ctrl.modify(|_, w| w.ovr()._1_0v()),
+            Ovr::V1_1 => pwrseq.lp_ctrl.modify(|_, w| w.ovr()._1_1v()),
+        }
+        self.ovr = ovr;
+        // A previous calibration no longer applies to the new frequency
+        self.hfio_cal = None;
+
+        // Wait for the HFIO to settle at its new frequency
+        let settled = if self.clkcn().read().hirc_en().is_en() {
+            wait_for(
+                self.running_sysclk(),
+                Hfio.timeout_ms(),
+                || Hfio.is_ready(self.clkcn()),
+                Error::HfioNotReady,
+            )
+        } else {
+            Ok(())
+        };
+
+        let result = settled.and_then(|_| self.set_sys_osc_source(self.sys_src));
+        if result.is_err() {
+            self.sys_src = ClkSrc::Nano;
+        }
+        self.update_freqs();
+        result
+    }
+
+    /// Operating voltage range currently selected.
+    pub fn ovr(&self) -> Ovr {
+        self.ovr
+    }
+
+    /// High Frequency Internal Oscillator frequency in Hz.
+    ///
+    /// This is the measured frequency once [`Clocks::calibrate_hfio`] has run,
+    /// otherwise the nominal frequency for the current OVR.
+    pub fn hfio(&self) -> u32 {
+        self.hfio_cal.unwrap_or_else(|| self.ovr.hfio_freq())
+    }
+
+    /// Measures the HFIO against the 32.768KHz crystal and updates the frozen
+    /// frequencies with the result, which is also returned (in Hz).
+    ///
+    /// The core cycle counter is sampled across `ticks` periods of the RTC
+    /// sub-second counter (256Hz), so the system clock must be running from
+    /// the HFIO and the RTC must be enabled. Interrupts are masked during the
+    /// measurement, which takes `ticks / 256` seconds.
+    ///
+    /// The result is accurate to the crystal tolerance plus a few core cycles
+    /// of edge detection per measurement, e.g. below 1ppm for 64 ticks at
+    /// 96MHz.
+    pub fn calibrate_hfio(
+        &mut self,
+        rtc: &Rtc,
+        dwt: &mut DWT,
+        dcb: &mut DCB,
+        ticks: u8,
+    ) -> Result<u32, Error> {
+        if self.sys_src != ClkSrc::Hfio {
+            return Err(Error::NotRunningFromHfio);
+        }
+        if !rtc.is_running() {
+            return Err(Error::RtcNotRunning);
+        }
+        let ticks = u32::from(ticks.max(1));
+
+        dcb.enable_trace();
+        dwt.enable_cycle_counter();
+
+        let sysclk = self.running_sysclk();
+        let cycles = interrupt::free(|_| -> Result<u32, Error> {
+            let next_tick = |last: u8| -> Result<u8, Error> {
+                let mut now = last;
+                // A sub-second tick takes under 4ms
+                wait_for(
+                    sysclk,
+                    READY_TIMEOUT_MS,
+                    || {
+                        now = rtc.sub_seconds();
+                        now != last
+                    },
+                    Error::X32kNotReady,
+                )?;
+                Ok(now)
+            };
+
+            // Synchronise to a sub-second edge before starting the count
+            let mut ssec = next_tick(rtc.sub_seconds())?;
+            let start = DWT::cycle_count();
+            for _ in 0..ticks {
+                ssec = next_tick(ssec)?;
+            }
+            Ok(DWT::cycle_count().wrapping_sub(start))
+        })?;
+
+        // Core cycles per second, scaled back up through the prescaler
+        let sysclk = u64::from(cycles) * 256 / u64::from(ticks);
+        let hfio = (sysclk << self.psc.shift()) as u32;
+        self.hfio_cal = Some(hfio);
+        self.update_freqs();
+        Ok(hfio)
+    }
+
+    /// Enables an oscillator, e.g. X32K for the RTC, and waits for it to be ready.
+    pub fn enable(&self, clk_src: ClkSrc) -> Result<(), Error> {
+        let osc: &dyn Oscillator = match clk_src {
+            ClkSrc::Hfio => &Hfio,
+            // 8KHz Nano-Ring oscillator which cannot be disabled.
+            ClkSrc::Nano => return Ok(()),
+            ClkSrc::X32k => &X32k,
+        };
+        osc.enable(self.clkcn());
+        wait_for(
+            self.running_sysclk(),
+            osc.timeout_ms(),
+            || osc.is_ready(self.clkcn()),
+            osc.not_ready(),
+        )
+    }
+
+    /// Checks if an oscillator is enabled and ready.
+    pub fn is_ready(&self, clk_src: ClkSrc) -> bool {
+        match clk_src {
+            ClkSrc::Hfio => Hfio.is_ready(self.clkcn()),
+            ClkSrc::Nano => true,
+            ClkSrc::X32k => X32k.is_ready(self.clkcn()),
+        }
+    }
+
+    /// Disables an oscillator. The system clock source is left running.
+    pub fn disable(&self, clk_src: ClkSrc) {
+        if clk_src == self.sys_src {
+            return;
+        }
+        match clk_src {
+            ClkSrc::Hfio => Hfio.disable(self.clkcn()),
+            ClkSrc::Nano => (),
+            ClkSrc::X32k => X32k.disable(self.clkcn()),
         }
     }
+
+    /// Oscillator currently driving the system clock.
+    pub fn sys_osc_source(&self) -> ClkSrc {
+        self.sys_src
+    }
+
+    /// System clock prescaler currently applied.
+    pub fn sys_osc_prescaler(&self) -> Div {
+        self.psc
+    }
+
+    /// System clock (SYSCLK) frequency in Hz.
+    pub fn sysclk(&self) -> u32 {
+        self.sysclk
+    }
+
+    /// Peripheral clock (PCLK) frequency in Hz.
+    pub fn pclk(&self) -> u32 {
+        self.pclk
+    }
+
+    /// Low frequency (X32K) clock frequency in Hz.
+    pub fn lfclk(&self) -> u32 {
+        LFCLK_FREQ
+    }
+
+    /// Un-gates the clock of a peripheral block.
+    pub fn enable_peripheral<P: PeripheralClock>(&self) {
+        interrupt::free(|_| P::set_gated(&self.gcr, false));
+    }
+
+    /// Gates the clock of a peripheral block to save power.
+    pub fn disable_peripheral<P: PeripheralClock>(&self) {
+        interrupt::free(|_| P::set_gated(&self.gcr, true));
+    }
+
+    /// Resets a peripheral block, blocking until the reset completes.
+    pub fn reset_peripheral<P: PeripheralReset>(&self) {
+        P::reset(&self.gcr);
+    }
+}
+
+/// Peripheral blocks with a clock gate in GCR PERCKCN0/1.
+pub trait PeripheralClock {
+    #[doc(hidden)]
+    fn set_gated(gcr: &gcr::RegisterBlock, gated: bool);
+}
+
+/// Peripheral blocks with a reset in GCR RSTR0/1.
+pub trait PeripheralReset {
+    #[doc(hidden)]
+    fn reset(gcr: &gcr::RegisterBlock);
+}
+
+macro_rules! peripheral_clocks {
+    ([$(($PER: ident, $perckcn: ident, $field: ident),)+]) => {
+        $(
+            impl PeripheralClock for $PER {
+                fn set_gated(gcr: &gcr::RegisterBlock, gated: bool) {
+                    gcr.$perckcn.modify(|_, w| w.$field().bit(gated));
+                }
+            }
+        )+
+    };
+}
+
+peripheral_clocks!([
+    (GPIO0, perckcn0, gpio0d),
+    (DMA, perckcn0, dmad),
+    (SPI17Y, perckcn0, spi0d),
+    (SPIMSS, perckcn0, spi1d),
+    (UART0, perckcn0, uart0d),
+    (UART1, perckcn0, uart1d),
+    (I2C0, perckcn0, i2c0d),
+    (I2C1, perckcn0, i2c1d),
+    (TMR0, perckcn0, t0d),
+    (TMR1, perckcn0, t1d),
+    (TMR2, perckcn0, t2d),
+    (FLC, perckcn1, flcd),
+    (ICC0, perckcn1, icached),
+]);
+
+macro_rules! peripheral_resets {
+    ([$(($PER: ident, $rstr: ident, $field: ident),)+]) => {
+        $(
+            impl PeripheralReset for $PER {
+                fn reset(gcr: &gcr::RegisterBlock) {
+                    // Writing zero to the other reset bits has no effect.
+                    gcr.$rstr.write(|w| w.$field().reset());
+                    while gcr.$rstr.read().$field().is_busy() {}
+                }
+            }
+        )+
+    };
+}
+
+peripheral_resets!([
+    (DMA, rstr0, dma),
+    (WDT0, rstr0, wdt),
+    (GPIO0, rstr0, gpio0),
+    (TMR0, rstr0, timer0),
+    (TMR1, rstr0, timer1),
+    (TMR2, rstr0, timer2),
+    (UART0, rstr0, uart0),
+    (UART1, rstr0, uart1),
+    (SPI17Y, rstr0, spi0),
+    (SPIMSS, rstr0, spi1),
+    (I2C0, rstr0, i2c0),
+    (RTC, rstr0, rtc),
+    (I2C1, rstr1, i2c1),
+]);
+
+/// Gates the clock of a peripheral outside of [`Clocks`], used by `free()` and `Drop`.
+pub(crate) fn gate_peripheral<P: PeripheralClock>() {
+    interrupt::free(|_| P::set_gated(unsafe { &*GCR::ptr() }, true));
 }
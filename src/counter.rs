@@ -0,0 +1,145 @@
+//! External event counting on the timer I/O pins.
+//!
+//! In counter mode the timer counts edges on its input pin instead of PCLK
+//! cycles, so pulses are counted without waking the core. The interrupt
+//! flag is raised once the count reaches the threshold, after which the
+//! count restarts.
+use crate::capture::Edge;
+use crate::clocks::{gate_peripheral, Clocks};
+use crate::gpio::{AltFn, AltMode, Floating, Input, Pin, AF3};
+use crate::pac::{TMR0, TMR1, TMR2};
+use crate::timer::{Instance, Prescaler, TMR0_IO, TMR1_IO, TMR2_IO};
+use cortex_m::peripheral::NVIC;
+
+pub struct Counter<TMR, AF: AltMode, const IDX: u8> {
+    tmr: TMR,
+    pin: Pin<AF, Input<Floating>, IDX>,
+}
+
+impl<TMR: Instance, AF: AltMode, const IDX: u8> Counter<TMR, AF, IDX> {
+    fn configure(
+        tmr: TMR,
+        pin: Pin<AF, Input<Floating>, IDX>,
+        clocks: &Clocks,
+        edge: Edge,
+        threshold: u32,
+    ) -> Self {
+        clocks.enable_peripheral::<TMR>();
+        clocks.reset_peripheral::<TMR>();
+        tmr.cn.modify(|_, w| w.tmode().counter());
+        let mut counter = Self { tmr, pin };
+        counter.set_edge(edge);
+        counter.set_threshold(threshold);
+        counter
+    }
+
+    /// Stops counting, gates the timer clock and releases the timer and pin.
+    pub fn free(mut self) -> (TMR, Pin<AF, Input<Floating>, IDX>) {
+        self.stop();
+        self.unlisten();
+        gate_peripheral::<TMR>();
+        (self.tmr, self.pin)
+    }
+
+    /// Selects which input edge is counted.
+    pub fn set_edge(&mut self, edge: Edge) {
+        match edge {
+            Edge::Rising => self.tmr.cn.modify(|_, w| w.tpol().active_hi()),
+            Edge::Falling => self.tmr.cn.modify(|_, w| w.tpol().active_lo()),
+        }
+    }
+
+    /// Counts one in every 2^n edges, e.g. to count beyond 2^32 events.
+    pub fn set_prescaler(&mut self, psc: Prescaler) {
+        psc.write(&self.tmr);
+    }
+
+    /// Number of edges after which the interrupt flag is raised and the
+    /// count restarts, at least one. Like the timer periods this is the
+    /// compare value, see the `timer` module.
+    pub fn set_threshold(&mut self, threshold: u32) {
+        self.tmr.cmp.write(|w| unsafe { w.bits(threshold.max(1)) });
+    }
+
+    /// Starts counting from zero.
+    pub fn start(&mut self) {
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+        self.reset();
+        self.clear_interrupt();
+        self.tmr.cn.modify(|_, w| w.ten().en());
+    }
+
+    /// Stops counting, keeping the current count.
+    pub fn stop(&mut self) {
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+    }
+
+    /// Continues counting after [`Counter::stop`].
+    pub fn resume(&mut self) {
+        self.tmr.cn.modify(|_, w| w.ten().en());
+    }
+
+    /// Edges counted since the start or the last threshold match, which
+    /// reads as the threshold until the next edge.
+    pub fn count(&self) -> u32 {
+        self.tmr.cnt.read().bits()
+    }
+
+    /// Restarts the count from zero.
+    pub fn reset(&mut self) {
+        self.tmr.cnt.write(|w| unsafe { w.bits(0) });
+    }
+
+    /// Enables the threshold interrupt in the NVIC.
+    pub fn listen(&mut self) {
+        unsafe { NVIC::unmask(TMR::INTERRUPT) };
+    }
+
+    /// Disables the threshold interrupt in the NVIC.
+    pub fn unlisten(&mut self) {
+        NVIC::mask(TMR::INTERRUPT);
+    }
+
+    /// Check if the threshold was reached.
+    pub fn pending_interrupt(&self) -> bool {
+        self.tmr.intr.read().irq_clr().bit_is_set()
+    }
+
+    /// Clear the threshold interrupt flag.
+    pub fn clear_interrupt(&mut self) {
+        self.tmr.intr.write(|w| w.irq_clr().set_bit());
+    }
+}
+
+macro_rules! counter_pins {
+    (
+        [$(($new: ident, $TMR: ident, $AF: ident, $idx: expr),)+]
+    ) => {
+        $(
+            impl Counter<$TMR, $AF, $idx> {
+                /// Claims the timer input pin for counting `edge` transitions,
+                /// flagging an interrupt every `threshold` edges. Counting
+                /// begins with [`Counter::start`].
+                pub fn $new<A: AltMode, IO>(
+                    tmr: $TMR,
+                    pin: Pin::<A, IO, $idx>,
+                    clocks: &Clocks,
+                    edge: Edge,
+                    threshold: u32
+                ) -> Self
+                where
+                    Pin<$AF, IO, $idx>: AltFn,
+                {
+                    let pin = pin.into_mode::<$AF>().into_floating_input();
+                    Self::configure(tmr, pin, clocks, edge, threshold)
+                }
+            }
+        )+
+    };
+}
+
+counter_pins!([
+    (tmr0, TMR0, AF3, TMR0_IO),
+    (tmr1, TMR1, AF3, TMR1_IO),
+    (tmr2, TMR2, AF3, TMR2_IO),
+]);
@@ -1,22 +1,68 @@
 //! Delays.
-// #FIXME HFCLK frequency assumption may be incorrect?
+//!
+//! [`Delay`] uses the system timer (SysTick). [`TimerDelay`] uses one of the
+//! 32-bit TMR blocks instead, leaving SysTick free for an RTOS or RTIC.
 use cortex_m::peripheral::syst::SystClkSource;
 use cortex_m::peripheral::SYST;
 use embedded_hal::blocking::delay::{DelayMs, DelayUs};
 
-use crate::clocks::HFCLK_FREQ;
+use crate::clocks::{gate_peripheral, Clocks};
+use crate::timer::Instance;
+
+/// Nanosecond delays for sub-microsecond busy-waits.
+///
+/// Delays are at least `ns` long; register access overhead dominates below a
+/// few hundred nanoseconds.
+pub trait DelayNs {
+    fn delay_ns(&mut self, ns: u32);
+}
 
 /// System timer (SysTick) as a delay provider.
 pub struct Delay {
     syst: SYST,
+    sysclk: u32,
 }
 
 impl Delay {
     /// Configures the system timer (SysTick) as a delay provider.
-    pub fn new(mut syst: SYST) -> Self {
+    pub fn new(mut syst: SYST, clocks: &Clocks) -> Self {
         syst.set_clock_source(SystClkSource::Core);
 
-        Delay { syst }
+        Delay {
+            syst,
+            sysclk: clocks.sysclk(),
+        }
+    }
+
+    /// Follows a change of the system clock, e.g. after `Clocks::set_ovr`.
+    pub fn update_clocks(&mut self, clocks: &Clocks) {
+        self.sysclk = clocks.sysclk();
+    }
+
+    /// Number of SysTick ticks for a delay of `count / per_sec` seconds,
+    /// rounded up so that slow clocks never shorten a delay to zero.
+    fn ticks(&self, count: u32, per_sec: u64) -> u64 {
+        (u64::from(count) * u64::from(self.sysclk)).div_ceil(per_sec)
+    }
+
+    fn delay_ticks(&mut self, mut total_rvr: u64) {
+        // The SysTick Reload Value register supports values between 1 and 0x00FFFFFF.
+        const MAX_RVR: u64 = 0x00FF_FFFF;
+
+        while total_rvr != 0 {
+            let current_rvr = total_rvr.min(MAX_RVR);
+
+            self.syst.set_reload(current_rvr as u32);
+            self.syst.clear_current();
+            self.syst.enable_counter();
+
+            // Update the tracking variable while we are waiting...
+            total_rvr -= current_rvr;
+
+            while !self.syst.has_wrapped() {}
+
+            self.syst.disable_counter();
+        }
     }
 
     /// Releases the system timer (SysTick) resource.
@@ -27,37 +73,110 @@ impl Delay {
 
 impl DelayMs<u32> for Delay {
     fn delay_ms(&mut self, ms: u32) {
-        for _ in 0..ms {
-            self.delay_us(1_000u32);
-        }
+        let ticks = self.ticks(ms, 1_000);
+        self.delay_ticks(ticks);
     }
 }
 
 impl DelayUs<u32> for Delay {
     fn delay_us(&mut self, us: u32) {
-        // The SysTick Reload Value register supports values between 1 and 0x00FFFFFF.
-        const MAX_RVR: u32 = 0x00FF_FFFF;
+        let ticks = self.ticks(us, 1_000_000);
+        self.delay_ticks(ticks);
+    }
+}
 
-        let mut total_rvr = us * (HFCLK_FREQ / 1_000_000);
+impl DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        let ticks = self.ticks(ns, 1_000_000_000);
+        self.delay_ticks(ticks);
+    }
+}
 
-        while total_rvr != 0 {
-            let current_rvr = if total_rvr <= MAX_RVR {
-                total_rvr
-            } else {
-                MAX_RVR
-            };
+/// 32-bit timer (TMR0-2) as a delay provider, counting at PCLK.
+pub struct TimerDelay<TMR> {
+    tmr: TMR,
+    pclk: u32,
+}
 
-            self.syst.set_reload(current_rvr);
-            self.syst.clear_current();
-            self.syst.enable_counter();
+impl<TMR: Instance> TimerDelay<TMR> {
+    /// Configures a timer in one-shot mode as a delay provider.
+    pub fn new(tmr: TMR, clocks: &Clocks) -> Self {
+        clocks.enable_peripheral::<TMR>();
+        clocks.reset_peripheral::<TMR>();
+        tmr.cn.write(|w| w.tmode().one_shot().pres().div1().pres3().clear_bit());
 
-            // Update the tracking variable while we are waiting...
-            total_rvr -= current_rvr;
+        TimerDelay {
+            tmr,
+            pclk: clocks.pclk(),
+        }
+    }
 
-            while !self.syst.has_wrapped() {}
+    /// Follows a change of the peripheral clock, e.g. after `Clocks::set_ovr`.
+    pub fn update_clocks(&mut self, clocks: &Clocks) {
+        self.pclk = clocks.pclk();
+    }
 
-            self.syst.disable_counter();
+    /// Releases the timer resource, gating its clock.
+    pub fn free(self) -> TMR {
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+        gate_peripheral::<TMR>();
+        self.tmr
+    }
+
+    /// Number of timer ticks for a delay of `count / per_sec` seconds, rounded up.
+    fn ticks(&self, count: u32, per_sec: u64) -> u64 {
+        (u64::from(count) * u64::from(self.pclk)).div_ceil(per_sec)
+    }
+
+    fn delay_ticks(&mut self, mut total_ticks: u64) {
+        while total_ticks != 0 {
+            let current_ticks = total_ticks.min(u64::from(u32::MAX - 1));
+
+            // One-shot mode counts up from 1 to the compare value, then stops
+            // and raises its interrupt flag.
+            self.tmr.cn.modify(|_, w| w.ten().dis());
+            self.tmr.cnt.write(|w| unsafe { w.bits(1) });
+            self.tmr
+                .cmp
+                .write(|w| unsafe { w.bits(current_ticks as u32 + 1) });
+            self.tmr.intr.write(|w| w.irq_clr().set_bit());
+            self.tmr.cn.modify(|_, w| w.ten().en());
+
+            total_ticks -= current_ticks;
+
+            while self.tmr.intr.read().irq_clr().bit_is_clear() {}
         }
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+    }
+}
+
+impl<TMR> DelayMs<u32> for TimerDelay<TMR>
+where
+    TMR: Instance,
+{
+    fn delay_ms(&mut self, ms: u32) {
+        let ticks = self.ticks(ms, 1_000);
+        self.delay_ticks(ticks);
+    }
+}
+
+impl<TMR> DelayUs<u32> for TimerDelay<TMR>
+where
+    TMR: Instance,
+{
+    fn delay_us(&mut self, us: u32) {
+        let ticks = self.ticks(us, 1_000_000);
+        self.delay_ticks(ticks);
+    }
+}
+
+impl<TMR> DelayNs for TimerDelay<TMR>
+where
+    TMR: Instance,
+{
+    fn delay_ns(&mut self, ns: u32) {
+        let ticks = self.ticks(ns, 1_000_000_000);
+        self.delay_ticks(ticks);
     }
 }
 
@@ -68,6 +187,15 @@ macro_rules! delay_xs {
                 self.$dxs($xs as u32);
             }
         }
+
+        impl<TMR> $DXS<$T> for TimerDelay<TMR>
+        where
+            TMR: Instance,
+        {
+            fn $dxs(&mut self, $xs: $T) {
+                self.$dxs($xs as u32);
+            }
+        }
     };
 }
 
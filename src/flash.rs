@@ -0,0 +1,472 @@
+//! Internal flash programming through the flash controller (FLC).
+//!
+//! The 256KB main flash is mapped from address 0 and split into 8KB pages.
+//! Flash bits can only be programmed from 1 to 0, so a page must be erased
+//! before a word in it is written again. Addresses passed to the driver are
+//! flash addresses, which are also the offsets used by the
+//! `embedded_storage` traits.
+//!
+//! While a program or erase operation is running the flash cannot be read,
+//! so an instruction or vector fetch from flash would fail. Operations are
+//! therefore started and waited on by a routine running from RAM with
+//! interrupts masked, which makes it safe to update the flash the program
+//! runs from. That routine is placed in the `.data` section, which the
+//! cortex-m-rt linker script (`link.x`) puts in RAM and copies there from
+//! flash at startup. Custom linker scripts must keep `*(.data .data.*)` in
+//! RAM with a load address in flash (`> RAM AT > FLASH`).
+//!
+//! The FLC has no per-page write protection, so page locks are enforced by
+//! the driver: programming or erasing a locked page returns
+//! [`Error::Locked`]. The information block, which holds factory data, is
+//! protected by the FLC access control register and can only be programmed
+//! or erased after [`Flash::unlock_info_block`].
+//!
+//! The information block also holds the factory unique serial number, read
+//! with [`Flash::usn`]. The factory trim values stored there are applied by
+//! the boot ROM and their layout is not documented, so they are only
+//! available as raw words through [`Flash::read_info`].
+use crate::clocks::{gate_peripheral, Clocks};
+use crate::pac::{flc, FLC, GCR};
+use cortex_m::interrupt;
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// Start of the main flash.
+pub const FLASH_BASE: u32 = 0x0000_0000;
+/// Size of the main flash (in bytes).
+pub const FLASH_SIZE: u32 = 0x0004_0000;
+/// Size of an erasable page (in bytes).
+pub const PAGE_SIZE: u32 = 0x2000;
+/// Number of main flash pages.
+pub const PAGE_COUNT: u32 = FLASH_SIZE / PAGE_SIZE;
+/// Start of the information block.
+pub const INFO_BASE: u32 = 0x0004_0000;
+/// Size of the information block (in bytes), a single page.
+pub const INFO_SIZE: u32 = 0x2000;
+
+/// Length of the unique serial number (in bytes).
+pub const USN_LEN: usize = 11;
+
+/// ACNTL key sequence enabling information block access. Writing anything
+/// else disables it again.
+const INFO_KEYS: [u32; 3] = [0x3a7f_5ca3, 0xa1e3_4f20, 0x9608_b2c1];
+
+/// The FLC needs a 1MHz clock for its program and erase timing.
+const FLC_CLK_FREQ: u32 = 1_000_000;
+
+// Register bits used from RAM, where the PAC accessors cannot be called
+const CN_WR: u32 = 1 << 0;
+const CN_ME: u32 = 1 << 1;
+const CN_PGE: u32 = 1 << 2;
+const CN_PEND: u32 = 1 << 24;
+const CN_BUSY: u32 = CN_WR | CN_ME | CN_PGE | CN_PEND;
+const CN_UNLOCK: u32 = 0xf << 28;
+const CN_LOCKED: u32 = 3 << 28;
+const SCON_CCACHE_FLUSH: u32 = 1 << 6;
+const SECOND_PAGE: u32 = FLASH_BASE + PAGE_SIZE;
+
+/// Flash errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The address or length is not a multiple of the access size.
+    NotAligned,
+    /// The address range is outside the flash.
+    OutOfBounds,
+    /// The FLC rejected the operation, e.g. it was started while busy.
+    AccessFail,
+    /// The range holds a locked page, or the locked information block.
+    Locked,
+    /// The system clock is below 1MHz, too slow to program or erase.
+    ClockTooSlow,
+}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::NotAligned => NorFlashErrorKind::NotAligned,
+            Error::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Start address of the page holding `addr`.
+pub fn page_address(addr: u32) -> u32 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+/// Reads a flash word or register. Flash starts at address 0, which Rust
+/// treats as the null pointer, so on the target the load is done in assembly.
+#[inline(always)]
+fn read_word(addr: u32) -> u32 {
+    #[cfg(target_arch = "arm")]
+    unsafe {
+        let word: u32;
+        core::arch::asm!(
+            "ldr {0}, [{1}]",
+            out(reg) word,
+            in(reg) addr,
+            options(readonly, nostack, preserves_flags)
+        );
+        word
+    }
+    #[cfg(not(target_arch = "arm"))]
+    unsafe {
+        core::ptr::read_volatile(addr as *const u32)
+    }
+}
+
+/// Writes a register. Like [`read_word`] this is done in assembly on the
+/// target, so nothing is called from flash by [`start_and_wait`].
+#[inline(always)]
+unsafe fn write_word(addr: u32, word: u32) {
+    #[cfg(target_arch = "arm")]
+    core::arch::asm!(
+        "str {0}, [{1}]",
+        in(reg) word,
+        in(reg) addr,
+        options(nostack, preserves_flags)
+    );
+    #[cfg(not(target_arch = "arm"))]
+    core::ptr::write_volatile(addr as *mut u32, word);
+}
+
+fn check_range(addr: u32, len: u32) -> Result<(), Error> {
+    match addr.checked_add(len) {
+        Some(end) if end <= FLASH_BASE + FLASH_SIZE => Ok(()),
+        _ => Err(Error::OutOfBounds),
+    }
+}
+
+fn is_info(addr: u32) -> bool {
+    (INFO_BASE..INFO_BASE + INFO_SIZE).contains(&addr)
+}
+
+/// Main flash pages overlapping `len` bytes from `addr`, one bit per page.
+/// The range must be checked.
+fn page_mask(addr: u32, len: u32) -> u32 {
+    if len == 0 {
+        return 0;
+    }
+    let first = (addr - FLASH_BASE) / PAGE_SIZE;
+    let last = (addr - FLASH_BASE + len - 1) / PAGE_SIZE;
+    (u32::MAX >> (PAGE_COUNT - 1 - last)) & (u32::MAX << first)
+}
+
+pub struct Flash {
+    flc: FLC,
+    locked: u32,
+    info_unlocked: bool,
+    clock_ok: bool,
+}
+
+impl Flash {
+    /// Un-gates the FLC and sets its clock divider from the system clock.
+    pub fn new(flc: FLC, clocks: &Clocks) -> Self {
+        clocks.enable_peripheral::<FLC>();
+        let mut flash = Self {
+            flc,
+            locked: 0,
+            info_unlocked: false,
+            clock_ok: false,
+        };
+        flash.update_clocks(clocks);
+        flash
+    }
+
+    /// Follows a change of the system clock, e.g. after `Clocks::set_ovr`.
+    /// Below 1MHz programming and erasing fail with [`Error::ClockTooSlow`].
+    pub fn update_clocks(&mut self, clocks: &Clocks) {
+        // Rounded up, so the FLC clock never runs above 1MHz
+        let div = clocks.sysclk().div_ceil(FLC_CLK_FREQ);
+        self.clock_ok = clocks.sysclk() >= FLC_CLK_FREQ;
+        self.flc.clkdiv.write(|w| unsafe { w.clkdiv().bits(div.max(1) as u8) });
+    }
+
+    /// Gates the FLC clock and releases the FLC resource. Flash stays readable.
+    /// Page locks are dropped with the driver.
+    pub fn free(mut self) -> FLC {
+        self.lock_info_block();
+        gate_peripheral::<FLC>();
+        self.flc
+    }
+
+    /// Write-locks the main flash pages overlapping `from..to`, e.g. the
+    /// bootloader. Locks last as long as the driver, there is no unlock.
+    pub fn lock_pages(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        if from > to {
+            return Err(Error::OutOfBounds);
+        }
+        check_range(from, to - from)?;
+        self.locked |= page_mask(from, to - from);
+        Ok(())
+    }
+
+    /// Checks if the main flash page holding `addr` is write-locked.
+    pub fn is_locked(&self, addr: u32) -> bool {
+        check_range(addr, 1).is_ok() && self.locked & page_mask(addr, 1) != 0
+    }
+
+    /// Enables programming and erasing the information block. Erasing it
+    /// loses the factory data it holds.
+    pub fn unlock_info_block(&mut self) {
+        for key in INFO_KEYS {
+            self.flc.acntl.write(|w| unsafe { w.bits(key) });
+        }
+        self.info_unlocked = true;
+    }
+
+    /// Disables access to the information block again.
+    pub fn lock_info_block(&mut self) {
+        self.flc.acntl.write(|w| unsafe { w.bits(0) });
+        self.info_unlocked = false;
+    }
+
+    /// Reads words of the information block starting at the byte `offset`,
+    /// which must be 4 byte aligned. The block is unlocked for the read and
+    /// relocked afterwards, unless it was unlocked before.
+    ///
+    /// This is also the only access to the factory trim values. Their
+    /// offsets and encoding aren't in the public datasheet or user guide, so
+    /// there are no typed accessors for them. The boot ROM has already
+    /// applied them, they don't need to be read to run the device.
+    pub fn read_info(&mut self, offset: u32, words: &mut [u32]) -> Result<(), Error> {
+        if !offset.is_multiple_of(4) {
+            return Err(Error::NotAligned);
+        }
+        match offset.checked_add(4 * words.len() as u32) {
+            Some(end) if end <= INFO_SIZE => {}
+            _ => return Err(Error::OutOfBounds),
+        }
+        let unlocked = self.info_unlocked;
+        if !unlocked {
+            self.unlock_info_block();
+        }
+        for (addr, word) in (INFO_BASE + offset..).step_by(4).zip(words.iter_mut()) {
+            *word = read_word(addr);
+        }
+        if !unlocked {
+            self.lock_info_block();
+        }
+        Ok(())
+    }
+
+    /// Factory unique serial number, read from the first four words of the
+    /// information block.
+    pub fn usn(&mut self) -> [u8; USN_LEN] {
+        let mut info = [0; 4];
+        // Four words are always in range
+        let _ = self.read_info(0, &mut info);
+        // The serial number is packed in bits 15..31 of each word, words 1
+        // and 3 also use their low bits
+        [
+            (info[0] >> 15) as u8,
+            (info[0] >> 23) as u8,
+            ((info[1] & 0x7f) << 1 | info[0] >> 31) as u8,
+            (info[1] >> 7) as u8,
+            (info[1] >> 15) as u8,
+            (info[1] >> 23) as u8,
+            (info[2] >> 15) as u8,
+            (info[2] >> 23) as u8,
+            ((info[3] & 0x7f) << 1 | info[2] >> 31) as u8,
+            (info[3] >> 7) as u8,
+            (info[3] >> 15) as u8,
+        ]
+    }
+
+    /// Checks if `len` bytes from `addr` may be programmed or erased.
+    fn check_access(&self, addr: u32, len: u32) -> Result<(), Error> {
+        if is_info(addr) {
+            match addr.checked_add(len) {
+                Some(end) if end <= INFO_BASE + INFO_SIZE => {}
+                _ => return Err(Error::OutOfBounds),
+            }
+            if !self.info_unlocked {
+                return Err(Error::Locked);
+            }
+            return Ok(());
+        }
+        check_range(addr, len)?;
+        match self.locked & page_mask(addr, len) {
+            0 => Ok(()),
+            _ => Err(Error::Locked),
+        }
+    }
+
+    /// Checks if a program or erase operation is in progress.
+    pub fn is_busy(&self) -> bool {
+        let cn = self.flc.cn.read();
+        cn.pend().is_busy() || cn.wr().is_start() || cn.me().bit_is_set() || cn.pge().bit_is_set()
+    }
+
+    /// Reads `buf.len()` bytes starting at `addr`.
+    pub fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), Error> {
+        check_range(addr, buf.len() as u32)?;
+        for (addr, byte) in (addr..).zip(buf.iter_mut()) {
+            *byte = read_word(addr & !3).to_le_bytes()[(addr & 3) as usize];
+        }
+        Ok(())
+    }
+
+    /// Erases the page holding `addr`, setting all its bytes to 0xFF.
+    pub fn erase_page(&mut self, addr: u32) -> Result<(), Error> {
+        self.check_access(addr, 1)?;
+        self.operate(|flc| {
+            flc.addr.write(|w| unsafe { w.addr().bits(page_address(addr)) });
+            flc.cn.modify(|_, w| w.erase_code().erase_page());
+            CN_PGE
+        })
+    }
+
+    /// Erases the whole main flash, including the running program. Only
+    /// useful from code running in RAM, the debug port must be enabled.
+    /// Fails if any page is locked.
+    pub fn mass_erase(&mut self) -> Result<(), Error> {
+        self.check_access(FLASH_BASE, FLASH_SIZE)?;
+        self.operate(|flc| {
+            flc.cn.modify(|_, w| w.erase_code().erase_all());
+            CN_ME
+        })
+    }
+
+    /// Programs a 32-bit word at a 4 byte aligned address.
+    pub fn write_u32(&mut self, addr: u32, word: u32) -> Result<(), Error> {
+        if !addr.is_multiple_of(4) {
+            return Err(Error::NotAligned);
+        }
+        self.check_access(addr, 4)?;
+        self.operate(|flc| {
+            flc.addr.write(|w| unsafe { w.addr().bits(addr) });
+            flc.data[0].write(|w| unsafe { w.bits(word) });
+            flc.cn.modify(|_, w| w.wdth().size32());
+            CN_WR
+        })
+    }
+
+    /// Programs four 32-bit words at a 16 byte aligned address, which takes
+    /// about the same time as a single 32-bit word.
+    pub fn write_u128(&mut self, addr: u32, words: [u32; 4]) -> Result<(), Error> {
+        if !addr.is_multiple_of(16) {
+            return Err(Error::NotAligned);
+        }
+        self.check_access(addr, 16)?;
+        self.operate(|flc| {
+            flc.addr.write(|w| unsafe { w.addr().bits(addr) });
+            for (data, word) in flc.data.iter().zip(words) {
+                data.write(|w| unsafe { w.bits(word) });
+            }
+            flc.cn.modify(|_, w| w.wdth().size128());
+            CN_WR
+        })
+    }
+
+    /// Programs `bytes` at a 4 byte aligned address, using 128-bit writes
+    /// where possible. The length must be a multiple of 4.
+    pub fn write(&mut self, addr: u32, bytes: &[u8]) -> Result<(), Error> {
+        if !addr.is_multiple_of(4) || !bytes.len().is_multiple_of(4) {
+            return Err(Error::NotAligned);
+        }
+        self.check_access(addr, bytes.len() as u32)?;
+        let mut addr = addr;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let word = |i: usize| {
+                u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
+            };
+            if addr.is_multiple_of(16) && bytes.len() >= 16 {
+                self.write_u128(addr, [word(0), word(4), word(8), word(12)])?;
+                addr += 16;
+                bytes = &bytes[16..];
+            } else {
+                self.write_u32(addr, word(0))?;
+                addr += 4;
+                bytes = &bytes[4..];
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the FLC to be idle, as register writes are ignored while
+    /// busy, then unlocks it and sets up an operation. `setup` returns the CN
+    /// bit starting it, which is set from RAM with interrupts masked.
+    fn operate<F>(&mut self, setup: F) -> Result<(), Error>
+    where
+        F: FnOnce(&flc::RegisterBlock) -> u32,
+    {
+        if !self.clock_ok {
+            return Err(Error::ClockTooSlow);
+        }
+        while self.is_busy() {}
+        self.flc.intr.modify(|_, w| w.af().no_error().done().inactive());
+        self.flc.cn.modify(|_, w| w.unlock().unlocked());
+        let start = setup(&self.flc);
+        let cn = &self.flc.cn as *const _ as u32;
+        // GCR SCON is otherwise owned by `clocks::Clocks`, interrupts are masked
+        let scon = unsafe { &(*GCR::ptr()).scon as *const _ as u32 };
+        interrupt::free(|_| unsafe { start_and_wait(cn, start, scon) });
+        if self.flc.intr.read().af().is_error() {
+            self.flc.intr.modify(|_, w| w.af().no_error());
+            return Err(Error::AccessFail);
+        }
+        Ok(())
+    }
+}
+
+/// Starts a set up operation, waits for it and relocks the FLC. Then drops
+/// instruction cache lines and the flash line buffer, which may hold stale
+/// copies of the changed flash.
+///
+/// Runs from RAM and must not touch flash until the operation is done, so
+/// registers are accessed through raw addresses. Interrupts must be masked.
+#[link_section = ".data"]
+#[inline(never)]
+unsafe fn start_and_wait(cn: u32, start: u32, scon: u32) {
+    write_word(cn, read_word(cn) | start);
+    while read_word(cn) & CN_BUSY != 0 {}
+    write_word(cn, (read_word(cn) & !CN_UNLOCK) | CN_LOCKED);
+    write_word(scon, read_word(scon) | SCON_CCACHE_FLUSH);
+    while read_word(scon) & SCON_CCACHE_FLUSH != 0 {}
+    // Reads from two different pages refill the line buffer
+    read_word(FLASH_BASE);
+    read_word(SECOND_PAGE);
+}
+
+impl ErrorType for Flash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        Flash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_SIZE as usize
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        if !from.is_multiple_of(PAGE_SIZE) || !to.is_multiple_of(PAGE_SIZE) {
+            return Err(Error::NotAligned);
+        }
+        if from > to {
+            return Err(Error::OutOfBounds);
+        }
+        self.check_access(from, to - from)?;
+        for page in (from..to).step_by(PAGE_SIZE as usize) {
+            self.erase_page(page)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        Flash::write(self, offset, bytes)
+    }
+}
@@ -1,12 +1,13 @@
 //! Module to configure the GPIO pins as I/O and Alternate Functions (AF).
 //!
-use crate::pac::{gpio0 as gpio, GPIO0 as P0};
+use crate::pac::{gpio0 as gpio, GPIO0 as P0, PWRSEQ};
 /// | Package | Number of GPIO | Pins |
 /// |---------------------------------|
 /// | 16 WLP  | GPIO0[9:0]     |  10  |
 /// | 20 TQFN | GPIO0[13:0]    |  14  |
 /// | 24 TQFN | GPIO0[13:0]    |  14  |
 use core::marker::PhantomData;
+use cortex_m::interrupt;
 use embedded_hal::digital::v2::{InputPin, OutputPin, StatefulOutputPin};
 use void::Void;
 
@@ -153,6 +154,12 @@ pub struct Pin<AF: AltMode, IO, const IDX: u8> {
     _io: PhantomData<IO>,
 }
 
+impl<AF: AltMode, IO, const IDX: u8> Default for Pin<AF, IO, IDX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // `<MODE>` Must precede the type to remain generic.
 impl<AF: AltMode, IO, const IDX: u8> Pin<AF, IO, IDX> {
     pub fn new() -> Self {
@@ -172,8 +179,7 @@ impl<AF: AltMode, IO, const IDX: u8> Pin<AF, IO, IDX> {
     }
 
     fn block(&self) -> &gpio::RegisterBlock {
-        let ptr = unsafe { &*P0::ptr() };
-        ptr
+        unsafe { &*P0::ptr() }
     }
 
     pub fn into_mode<M: AltMode>(self) -> Pin<M, IO, IDX>
@@ -266,6 +272,34 @@ impl<MODE, const IDX: u8> Pin<Gpio, Input<MODE>, IDX> {
     pub fn clear_interrupt(&self){
         self.block().int_clr.write(|w|unsafe{ w.bits(0x01 << self.pin())});
     }
+
+    /// Allows a transition on this pin to wake the device from DeepSleep or Backup.
+    ///
+    /// GPIO wakeup must also be enabled with `power::Power::set_wakeup_sources`.
+    pub fn enable_wakeup(&self){
+        self.block().wake_en_set.write(|w| unsafe{w.bits(self.mask())});
+        interrupt::free(|_| {
+            let pwrseq = unsafe { &*PWRSEQ::ptr() };
+            pwrseq.lpwk_en.modify(|r, w| unsafe{w.bits(r.bits() | self.mask())});
+        });
+    }
+    pub fn disable_wakeup(&self){
+        self.block().wake_en_clr.write(|w| unsafe{w.bits(self.mask())});
+        interrupt::free(|_| {
+            let pwrseq = unsafe { &*PWRSEQ::ptr() };
+            pwrseq.lpwk_en.modify(|r, w| unsafe{w.bits(r.bits() & !self.mask())});
+        });
+    }
+    /// Check if this pin flagged a wakeup
+    pub fn pending_wakeup(&self) -> bool{
+        let pwrseq = unsafe { &*PWRSEQ::ptr() };
+        pwrseq.lp_wakefl.read().bits() & self.mask() != 0
+    }
+    pub fn clear_wakeup(&self){
+        // Write one to clear
+        let pwrseq = unsafe { &*PWRSEQ::ptr() };
+        pwrseq.lp_wakefl.write(|w| unsafe{w.bits(self.mask())});
+    }
 }
 
 impl<AF: AltMode, MODE, const IDX: u8> InputPin for Pin<AF, Input<MODE>, IDX> {
@@ -306,7 +340,7 @@ impl<AF: AltMode, MODE, const IDX: u8> StatefulOutputPin for Pin<AF, Output<MODE
 }
 
 impl<AF: AltMode, MODE, const IDX: u8> Pin<AF, Output<MODE>, IDX> {
-    pub fn set_drive_strength(&mut self, drive_strength: DriveStrength) -> () {
+    pub fn set_drive_strength(&mut self, drive_strength: DriveStrength) {
         let ds_settings = drive_strength.get_setting();
         let ds_val = ds_settings.ds as u32;
         let ds1_val = ds_settings.ds1 as u32;
@@ -359,7 +393,7 @@ macro_rules! gpio {
     };
 }
 
-#[cfg(any(feature = "pkg-wlp"))]
+#[cfg(feature = "pkg-wlp")]
 gpio!(
     P0,
     p0,
@@ -377,7 +411,7 @@ gpio!(
     ]
 );
 
-#[cfg(any(feature = "pkg-tqfn"))]
+#[cfg(feature = "pkg-tqfn")]
 gpio!(
     P0,
     p0,
@@ -1,19 +1,12 @@
 //! I2C implementation of the embedded_hal i2c traits and configuration
 //!
-use crate::clocks::PCLK_FREQ;
+use crate::clocks::{gate_peripheral, Clocks};
 use crate::gpio::{AltFn, AltMode, Pin, AF1, Input, Floating};
 use core::marker::PhantomData;
-use cortex_m::asm::nop;
-use embedded_hal::digital::v2::OutputPin;
-use embedded_hal::blocking::i2c;
 use heapless::Vec;
-use max32660_pac::i2c0::ctrl::SCL_PP_MODE_A;
 
 use crate::pac::i2c0;
-use crate::pac::I2C0 as I2C0;
-
-use nb;
-use void::Void;
+use crate::pac::I2C0;
 
 /// I2C Pins
 pub const I2C0_SCL: u8 = 8;
@@ -21,10 +14,6 @@ pub const I2C0_SDA: u8 = 9;
 // pub const I2C1_SCL: u8 = 2;
 // pub const I2C1_SDA: u8 = 3;
 
-// FIFO and DMA constants
-/// Assumes write word size is a u8 and only half the fifo is being used.
-const TX_FIFO_LEVEL: u8 = 16;
-
 #[derive(PartialEq)]
 pub enum Command{
     Read,
@@ -44,8 +33,8 @@ pub struct Pins<
     const SCL_IDX: u8,
     const SDA_IDX: u8,
 > {
-    scl: Pin<AF, Input<Floating>, SCL_IDX>,
-    sda: Pin<AF, Input<Floating>, SDA_IDX>
+    pub scl: Pin<AF, Input<Floating>, SCL_IDX>,
+    pub sda: Pin<AF, Input<Floating>, SDA_IDX>
 }
 
 pub struct I2CPort<
@@ -55,6 +44,7 @@ pub struct I2CPort<
     const SDA_IDX: u8,
 > {
     pins: Pins<AF, SCL_IDX, SDA_IDX>,
+    pclk: u32,
     _port: PhantomData<PORT>,
 }
 
@@ -90,17 +80,22 @@ impl<
 
 macro_rules! i2c_ports{
     (
-        [$(($new: ident, $AF: ident, $PORT: ident, $a: expr, $b: expr),)+]
+        [$(($new: ident, $AF: ident, $PORT: ident, $I2C: ident, $a: expr, $b: expr),)+]
     ) => {
         $(
             impl I2CPort<$AF, $PORT, $a, $b>{
                 pub fn $new<SA:AltMode, IA: AltMode, SM, IM>(
                     scl: Pin::<SA, SM, $a>,
-                    sda: Pin::<IA, IM, $b>
+                    sda: Pin::<IA, IM, $b>,
+                    clocks: &Clocks
                 ) -> Self {
+                    // Un-gate the I2C block and start from a clean state
+                    clocks.enable_peripheral::<$I2C>();
+                    clocks.reset_peripheral::<$I2C>();
                     let pins = Self::configure_pins(scl, sda);
                     Self{
-                        pins: pins,
+                        pins,
+                        pclk: clocks.pclk(),
                         _port: PhantomData,
                     }
                 }
@@ -110,7 +105,7 @@ macro_rules! i2c_ports{
 }
 
 i2c_ports!([
-    (new_i2c0_slave, AF1, I2C0Slave, I2C0_SCL, I2C0_SDA),
+    (new_i2c0_slave, AF1, I2C0Slave, I2C0, I2C0_SCL, I2C0_SDA),
 ]);
 
 
@@ -119,8 +114,7 @@ pub type I2CPort0Slave = I2CPort<AF1, I2C0Slave, I2C0_SCL, I2C0_SDA>;
 
 impl <AF: AltMode, P: I2CShared> I2CPort<AF, P, I2C0_SCL, I2C0_SDA> {
     fn block(&self) -> &i2c0::RegisterBlock {
-        let ptr = unsafe { &*I2C0::ptr() };
-        ptr
+        unsafe { &*I2C0::ptr() }
     }
 
     /// Enables the I2C Peripheral
@@ -143,6 +137,13 @@ impl <AF: AltMode, P: I2CShared> I2CPort<AF, P, I2C0_SCL, I2C0_SDA> {
         self.block().ctrl.modify(|_, w| w.i2c_en().dis());
     }
 
+    /// Disables the I2C Port, gates its clock and releases the pins.
+    pub fn free(mut self) -> Pins<AF, I2C0_SCL, I2C0_SDA> {
+        self.disable();
+        gate_peripheral::<I2C0>();
+        self.pins
+    }
+
     /// Check the I2C transaction type Read or Write 
     pub fn get_command(&mut self) -> Command{
         let read_command = self.block().ctrl.read().read().is_read();
@@ -269,9 +270,10 @@ impl I2CPort0Slave {
         self.block().tx_ctrl0.modify(|_, w| w.tx_preload().clear_bit());
 
         // 1/fpclk * (clk_hi + 1) > Tsu_data(min)
-        let clk_hi = 32u16; // Unknown Tsu_data
+        // Unknown Tsu_data, hold for ~680ns (32 cycles of the default 48MHz PCLK).
+        let clk_hi = ((self.pclk as u64 * 680 / 1_000_000_000) as u16).clamp(1, 0x1FF);
         self.block().clk_hi.modify(|_, w| unsafe{w.ckh().bits(clk_hi)});
-        let clk_hi_hs = 32u8; // Unknown Tsu_data
+        let clk_hi_hs = clk_hi.min(0xFF) as u8;
         self.block().hs_clk.modify(|_, w| unsafe{w.hs_clk_hi().bits(clk_hi_hs)});
 
         self.block().slave_addr.modify(|_, w|unsafe{w.slave_addr().bits(addr)});
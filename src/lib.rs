@@ -1,8 +1,20 @@
 #![no_std]
 pub use max32660_pac as pac;
 
+pub mod capture;
 pub mod clocks;
+pub mod counter;
 pub mod delay;
+pub mod flash;
 pub mod gpio;
 pub mod spi;
 pub mod i2c;
+pub mod power;
+pub mod pwm;
+pub mod rtc;
+pub mod serial;
+#[cfg(feature = "storage")]
+pub mod storage;
+pub mod system;
+pub mod timer;
+pub mod watchdog;
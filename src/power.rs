@@ -0,0 +1,220 @@
+//! Low power modes, wakeup sources and RAM retention.
+//!
+//! | Mode      | Entered by           | Exit                                      |
+//! |-----------|----------------------|-------------------------------------------|
+//! | Sleep     | `WFI`                | Any enabled interrupt                     |
+//! | DeepSleep | `SLEEPDEEP` + `WFI`  | Enabled wakeup sources                    |
+//! | Backup    | GCR PM.MODE = BACKUP | Enabled wakeup sources, restarts at reset |
+//!
+//! A wakeup source only wakes the core if its interrupt is also unmasked in
+//! the NVIC (GPIO0 for pins, RTC for alarms). Individual pins are selected
+//! with `gpio::Pin::enable_wakeup` and RTC alarms with
+//! [`Power::enable_rtc_wakeup`].
+use crate::clocks::{Clocks, Error, Ovr};
+use crate::pac::{gcr, GCR, PWRSEQ, RTC};
+use crate::rtc::Rtc;
+use cortex_m::asm::wfi;
+use cortex_m::interrupt;
+use cortex_m::peripheral::SCB;
+
+/// Low power modes that return to the caller once woken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepMode {
+    /// The core clock is stopped, peripherals keep running.
+    Sleep,
+    /// The core and most peripheral clocks are stopped.
+    DeepSleep,
+}
+
+/// Wakeup sources for DeepSleep and Backup modes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WakeupSources {
+    /// GPIO pins enabled for wakeup.
+    pub gpio: bool,
+    /// RTC time-of-day and sub-second alarms.
+    pub rtc: bool,
+}
+
+/// System RAM banks which can be retained in Backup mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamBank {
+    /// 0x2000_0000 - 0x2000_3FFF
+    Sram0,
+    /// 0x2000_4000 - 0x2000_7FFF
+    Sram1,
+    /// 0x2000_8000 - 0x2000_FFFF
+    Sram2,
+    /// 0x2001_0000 - 0x2001_7FFF
+    Sram3,
+}
+
+/// RTC alarms which can wake the device.
+pub use crate::rtc::Alarm as RtcAlarm;
+
+/// Record of what woke the device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WakeupCause {
+    /// Mask of the GPIO0 pins that flagged a wakeup.
+    pub gpio_pins: u16,
+    /// The RTC time-of-day alarm flag was set.
+    pub rtc_time_of_day: bool,
+    /// The RTC sub-second alarm flag was set.
+    pub rtc_sub_second: bool,
+}
+
+impl WakeupCause {
+    /// Checks if nothing flagged a wakeup, e.g. an interrupt woke the core from Sleep.
+    pub fn is_empty(&self) -> bool {
+        self.gpio_pins == 0 && !self.rtc_alarm()
+    }
+
+    /// Checks if either RTC alarm flagged a wakeup.
+    pub fn rtc_alarm(&self) -> bool {
+        self.rtc_time_of_day || self.rtc_sub_second
+    }
+
+    /// Checks if GPIO0 pin `idx` flagged a wakeup.
+    pub fn gpio_pin(&self, idx: u8) -> bool {
+        self.gpio_pins & (1 << idx) != 0
+    }
+}
+
+/// Power sequencer (PWRSEQ) and GCR power management.
+pub struct Power {
+    pwrseq: PWRSEQ,
+}
+
+impl Power {
+    pub fn new(pwrseq: PWRSEQ) -> Self {
+        Self { pwrseq }
+    }
+
+    /// Releases the power sequencer resource.
+    pub fn free(self) -> PWRSEQ {
+        self.pwrseq
+    }
+
+    /// GCR PM is only modified by this module, the rest of the GCR is owned by [`Clocks`].
+    fn pm(&self) -> &gcr::PM {
+        unsafe { &(*GCR::ptr()).pm }
+    }
+
+    /// Selects which sources may wake the device from DeepSleep and Backup.
+    pub fn set_wakeup_sources(&mut self, sources: WakeupSources) {
+        interrupt::free(|_| {
+            self.pm().modify(|_, w| {
+                w.gpiowken().bit(sources.gpio);
+                w.rtcwken().bit(sources.rtc)
+            })
+        });
+    }
+
+    /// Currently enabled wakeup sources.
+    pub fn wakeup_sources(&self) -> WakeupSources {
+        let pm = self.pm().read();
+        WakeupSources {
+            gpio: pm.gpiowken().is_en(),
+            rtc: pm.rtcwken().is_en(),
+        }
+    }
+
+    /// Selects whether a RAM bank keeps its contents in Backup mode.
+    pub fn set_ram_retention(&mut self, bank: RamBank, retain: bool) {
+        let lp_ctrl = &self.pwrseq.lp_ctrl;
+        match bank {
+            RamBank::Sram0 => lp_ctrl.modify(|_, w| w.ramret_sel0().bit(retain)),
+            RamBank::Sram1 => lp_ctrl.modify(|_, w| w.ramret_sel1().bit(retain)),
+            RamBank::Sram2 => lp_ctrl.modify(|_, w| w.ramret_sel2().bit(retain)),
+            RamBank::Sram3 => lp_ctrl.modify(|_, w| w.ramret_sel3().bit(retain)),
+        }
+        // The retention regulator is only needed while a bank is retained
+        let r = lp_ctrl.read();
+        let any = r.ramret_sel0().is_en()
+            || r.ramret_sel1().is_en()
+            || r.ramret_sel2().is_en()
+            || r.ramret_sel3().is_en();
+        lp_ctrl.modify(|_, w| w.retreg_en().bit(any));
+    }
+
+    /// Changes the operating voltage range, see [`Clocks::set_ovr`].
+    pub fn set_ovr(&self, clocks: &mut Clocks, ovr: Ovr) -> Result<(), Error> {
+        clocks.set_ovr(&self.pwrseq, ovr)
+    }
+
+    /// Reads which sources flagged a wakeup.
+    pub fn wakeup_cause(&self) -> WakeupCause {
+        let rtc = unsafe { &*RTC::ptr() }.ctrl.read();
+        WakeupCause {
+            gpio_pins: self.pwrseq.lp_wakefl.read().wakest().bits(),
+            rtc_time_of_day: rtc.aldf().bit_is_set(),
+            rtc_sub_second: rtc.alsf().bit_is_set(),
+        }
+    }
+
+    /// Clears the GPIO wakeup flags. RTC alarm flags are cleared by the RTC driver.
+    pub fn clear_wakeup_flags(&mut self) {
+        self.pwrseq
+            .lp_wakefl
+            .write(|w| unsafe { w.wakest().bits(0x3FFF) });
+    }
+
+    /// Enables an RTC alarm as a wakeup source.
+    ///
+    /// This enables the alarm interrupt in the RTC and RTC wakeup in GCR PM,
+    /// the alarm time itself is set through the RTC.
+    pub fn enable_rtc_wakeup(&mut self, rtc: &mut Rtc, alarm: RtcAlarm) {
+        rtc.listen(alarm);
+        interrupt::free(|_| self.pm().modify(|_, w| w.rtcwken().en()));
+    }
+
+    /// Disables an RTC alarm as a wakeup source, RTC wakeup in GCR PM is left
+    /// enabled while the other alarm is still in use.
+    pub fn disable_rtc_wakeup(&mut self, rtc: &mut Rtc, alarm: RtcAlarm) {
+        rtc.unlisten(alarm);
+        if !rtc.is_listening(RtcAlarm::TimeOfDay) && !rtc.is_listening(RtcAlarm::SubSecond) {
+            interrupt::free(|_| self.pm().modify(|_, w| w.rtcwken().dis()));
+        }
+    }
+
+    /// Enters Sleep or DeepSleep and returns what woke the device.
+    ///
+    /// Stale GPIO wakeup flags are cleared before entering the low power mode.
+    /// RTC alarm flags are left to the RTC driver, so only alarms that went
+    /// off while sleeping are reported: an alarm that was already flagged
+    /// before sleeping and goes off again is not. Clear alarms with
+    /// `Rtc::clear_alarm` before sleeping to see every alarm.
+    ///
+    /// Interrupts are masked around `WFI`, which still wakes the core, so
+    /// the cause is read before the wakeup interrupt handlers run and clear
+    /// their flags. The handlers run once this returns.
+    pub fn sleep(&mut self, scb: &mut SCB, mode: SleepMode) -> WakeupCause {
+        self.clear_wakeup_flags();
+        match mode {
+            SleepMode::Sleep => scb.clear_sleepdeep(),
+            SleepMode::DeepSleep => scb.set_sleepdeep(),
+        }
+        let (before, after) = interrupt::free(|_| {
+            let before = self.wakeup_cause();
+            wfi();
+            (before, self.wakeup_cause())
+        });
+        scb.clear_sleepdeep();
+        WakeupCause {
+            gpio_pins: after.gpio_pins,
+            rtc_time_of_day: after.rtc_time_of_day && !before.rtc_time_of_day,
+            rtc_sub_second: after.rtc_sub_second && !before.rtc_sub_second,
+        }
+    }
+
+    /// Enters Backup mode. Only retained RAM banks keep their contents and
+    /// the device restarts from reset on wakeup; use [`Power::wakeup_cause`]
+    /// after boot to find out why.
+    pub fn backup(mut self, scb: &mut SCB) -> ! {
+        self.clear_wakeup_flags();
+        scb.set_sleepdeep();
+        interrupt::free(|_| self.pm().modify(|_, w| w.mode().backup()));
+        loop {
+            wfi();
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! PWM output on the timer I/O pins, implementing the embedded_hal `PwmPin` trait.
+//!
+//! The timer counts from 1 up to the period (compare value) and the output
+//! changes level when the count passes the duty (PWM) value.
+use crate::clocks::{gate_peripheral, Clocks};
+use crate::gpio::{AltFn, AltMode, Level, Output, Pin, PushPull, AF3};
+use crate::pac::{TMR0, TMR1, TMR2};
+use crate::timer::{Hertz, Instance, TMR0_IO, TMR1_IO, TMR2_IO};
+use embedded_hal::PwmPin;
+
+/// PWM output polarity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    /// Output is high for the duty part of the period.
+    ActiveHigh,
+    /// Output is low for the duty part of the period.
+    ActiveLow,
+}
+
+pub struct Pwm<TMR, AF: AltMode, const IDX: u8> {
+    tmr: TMR,
+    pin: Pin<AF, Output<PushPull>, IDX>,
+    pclk: u32,
+}
+
+impl<TMR: Instance, AF: AltMode, const IDX: u8> Pwm<TMR, AF, IDX> {
+    fn configure(tmr: TMR, pin: Pin<AF, Output<PushPull>, IDX>, clocks: &Clocks, freq: Hertz) -> Self {
+        clocks.enable_peripheral::<TMR>();
+        clocks.reset_peripheral::<TMR>();
+        tmr.cn.modify(|_, w| w.tmode().pwm().tpol().active_hi());
+        let mut pwm = Self {
+            tmr,
+            pin,
+            pclk: clocks.pclk(),
+        };
+        pwm.set_frequency(freq);
+        pwm
+    }
+
+    /// Disables the PWM output, gates the timer clock and releases the timer and pin.
+    pub fn free(self) -> (TMR, Pin<AF, Output<PushPull>, IDX>) {
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+        gate_peripheral::<TMR>();
+        (self.tmr, self.pin)
+    }
+
+    /// Frequency the timer counts at (in Hz), PCLK as the prescaler is not used.
+    pub fn tick_freq(&self) -> u32 {
+        self.pclk
+    }
+
+    /// Sets the period in timer ticks. The duty is scaled to keep the same ratio.
+    pub fn set_period_ticks(&mut self, ticks: u32) {
+        let ticks = ticks.max(2);
+        let duty = (u64::from(self.get_duty()) * u64::from(ticks))
+            .checked_div(u64::from(self.get_max_duty()))
+            .unwrap_or(0) as u32;
+        self.tmr.cmp.write(|w| unsafe { w.bits(ticks) });
+        self.set_duty(duty);
+        // Restart the period so the new compare value is not missed
+        self.tmr.cnt.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Period in timer ticks.
+    pub fn get_period_ticks(&self) -> u32 {
+        self.tmr.cmp.read().bits()
+    }
+
+    /// Sets the PWM frequency.
+    pub fn set_frequency(&mut self, freq: Hertz) {
+        self.set_period_ticks(self.tick_freq() / freq.0.max(1));
+    }
+
+    /// Current PWM frequency.
+    pub fn get_frequency(&self) -> Hertz {
+        Hertz(self.tick_freq() / self.get_period_ticks().max(1))
+    }
+
+    /// Selects the output polarity.
+    pub fn set_polarity(&mut self, polarity: Polarity) {
+        match polarity {
+            Polarity::ActiveHigh => self.tmr.cn.modify(|_, w| w.tpol().active_hi()),
+            Polarity::ActiveLow => self.tmr.cn.modify(|_, w| w.tpol().active_lo()),
+        }
+    }
+}
+
+impl<TMR: Instance, AF: AltMode, const IDX: u8> PwmPin for Pwm<TMR, AF, IDX> {
+    type Duty = u32;
+
+    fn disable(&mut self) {
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+    }
+
+    fn enable(&mut self) {
+        self.tmr.cnt.write(|w| unsafe { w.bits(1) });
+        self.tmr.cn.modify(|_, w| w.ten().en());
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.tmr.pwm.read().bits()
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        self.get_period_ticks()
+    }
+
+    fn set_duty(&mut self, duty: u32) {
+        let duty = duty.min(self.get_max_duty());
+        self.tmr.pwm.write(|w| unsafe { w.bits(duty) });
+    }
+}
+
+macro_rules! pwm_pins {
+    (
+        [$(($new: ident, $TMR: ident, $AF: ident, $idx: expr),)+]
+    ) => {
+        $(
+            impl Pwm<$TMR, $AF, $idx> {
+                /// Claims the timer output pin and configures the timer in PWM
+                /// mode at `freq` with a duty of zero. The output starts disabled.
+                pub fn $new<A: AltMode, IO>(
+                    tmr: $TMR,
+                    pin: Pin::<A, IO, $idx>,
+                    clocks: &Clocks,
+                    freq: Hertz
+                ) -> Self
+                where
+                    Pin<$AF, IO, $idx>: AltFn,
+                {
+                    let pin = pin.into_mode::<$AF>().into_push_pull_output(Level::Low);
+                    Self::configure(tmr, pin, clocks, freq)
+                }
+            }
+        )+
+    };
+}
+
+pwm_pins!([
+    (tmr0, TMR0, AF3, TMR0_IO),
+    (tmr1, TMR1, AF3, TMR1_IO),
+    (tmr2, TMR2, AF3, TMR2_IO),
+]);
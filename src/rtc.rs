@@ -0,0 +1,427 @@
+//! Real time clock, counting seconds and 1/256 second ticks from the X32K.
+//!
+//! The RTC is powered with the always-on domain and keeps counting through
+//! resets and low power modes, so [`Rtc::new`] leaves the counter untouched.
+//! Time is kept as seconds since the Unix epoch, [`DateTime`] converts to
+//! and from a calendar date-time (UTC).
+//!
+//! For crystal calibration the RTC clock can be output on the square-wave
+//! pin and the measured error corrected with [`Rtc::correct_ppm`]. Only the
+//! 1Hz and 512Hz outputs include the trim correction, so measure the raw
+//! crystal error on the 4KHz or 32KHz output and verify on the others.
+use crate::clocks::{ClkSrc, Clocks, Error};
+use crate::gpio::{AltFn, AltMode, Level, Output, Pin, PushPull, AF3};
+use crate::pac::{rtc, Interrupt, RTC};
+use cortex_m::peripheral::NVIC;
+
+/// RTC square-wave output pin (alternate function 3).
+pub const RTC_SQW: u8 = 2;
+
+/// Frequency of the sub-second counter (in Hz).
+pub const SUB_SECOND_FREQ: u32 = 256;
+
+/// Largest trim correction (in ppm).
+pub const MAX_TRIM_PPM: i32 = 127;
+
+/// Time-of-day alarm interrupt flag.
+const ALDF: u32 = 1 << 6;
+/// Sub-second alarm interrupt flag.
+const ALSF: u32 = 1 << 7;
+
+/// RTC alarms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alarm {
+    /// Time-of-day alarm (RAS).
+    TimeOfDay,
+    /// Sub-second alarm (RSSA).
+    SubSecond,
+}
+
+/// Square-wave output frequencies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SquareWave {
+    /// 1Hz, trim compensated.
+    Hz1,
+    /// 512Hz, trim compensated.
+    Hz512,
+    /// 4.096KHz, uncompensated.
+    Hz4096,
+    /// 32.768KHz crystal clock, uncompensated.
+    Hz32768,
+}
+
+impl SquareWave {
+    /// Nominal frequency (in Hz).
+    pub fn freq(self) -> u32 {
+        match self {
+            SquareWave::Hz1 => 1,
+            SquareWave::Hz512 => 512,
+            SquareWave::Hz4096 => 4096,
+            SquareWave::Hz32768 => 32_768,
+        }
+    }
+}
+
+/// Error of a measured frequency in ppm, positive if the clock runs fast.
+/// The measurement is given in mHz for sub-Hz resolution on the slow outputs.
+pub fn ppm_error(wave: SquareWave, measured_mhz: u64) -> i32 {
+    let nominal = i64::from(wave.freq()) * 1000;
+    ((measured_mhz as i64 - nominal) * 1_000_000 / nominal) as i32
+}
+
+/// RTC count, read atomically.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    /// Seconds since the Unix epoch.
+    pub seconds: u32,
+    /// 1/256 second ticks.
+    pub sub_seconds: u8,
+}
+
+impl Timestamp {
+    /// Sub-seconds in milliseconds.
+    pub fn millis(&self) -> u16 {
+        (u32::from(self.sub_seconds) * 1000 / SUB_SECOND_FREQ) as u16
+    }
+}
+
+/// Calendar date and time (UTC), from 1970 to 2106.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    /// 0 to 23
+    pub hour: u8,
+    /// 0 to 59
+    pub minute: u8,
+    /// 0 to 59
+    pub second: u8,
+}
+
+fn is_leap_year(year: u32) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+fn days_in_month(year: u32, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Converts seconds since the Unix epoch.
+    pub fn from_timestamp(seconds: u32) -> Self {
+        // Civil from days, with years starting in March so the leap day is last
+        let days = seconds / 86_400 + 719_468;
+        let secs = seconds % 86_400;
+        let era = days / 146_097;
+        let doe = days % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u32::from(month <= 2);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+
+    /// Converts to seconds since the Unix epoch, `None` if the date-time is
+    /// invalid or out of range.
+    pub fn timestamp(&self) -> Option<u32> {
+        let year = u32::from(self.year);
+        if year < 1970
+            || !(1..=12).contains(&self.month)
+            || self.day == 0
+            || self.day > days_in_month(year, self.month)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 59
+        {
+            return None;
+        }
+        // Days from civil, the inverse of `from_timestamp`
+        let y = if self.month <= 2 { year - 1 } else { year };
+        let era = y / 400;
+        let yoe = y % 400;
+        let m = u32::from(self.month);
+        let mp = if m > 2 { m - 3 } else { m + 9 };
+        let doy = (153 * mp + 2) / 5 + u32::from(self.day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = u64::from(era * 146_097 + doe) - 719_468;
+        let seconds = days * 86_400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second);
+        u32::try_from(seconds).ok()
+    }
+
+    /// Day of the week, 0 for Sunday to 6 for Saturday.
+    pub fn weekday(&self) -> Option<u8> {
+        // 1970-01-01 was a Thursday
+        self.timestamp().map(|s| ((s / 86_400 + 4) % 7) as u8)
+    }
+}
+
+/// Waits for the RTC to synchronise a previous register change.
+fn wait_busy(rtc: &rtc::RegisterBlock) {
+    while rtc.ctrl.read().busy().bit_is_set() {}
+}
+
+/// Runs `f` with the protected RTC registers write enabled. Changes are
+/// only effective once BUSY clears.
+fn write_enabled<F: FnOnce()>(rtc: &rtc::RegisterBlock, f: F) {
+    wait_busy(rtc);
+    rtc.ctrl.modify(|_, w| w.we().pending());
+    wait_busy(rtc);
+    f();
+    wait_busy(rtc);
+    rtc.ctrl.modify(|_, w| w.we().inactive());
+}
+
+/// Changes the RTC control register.
+fn update_ctrl<F>(rtc: &rtc::RegisterBlock, f: F)
+where
+    F: FnOnce(&mut rtc::ctrl::W) -> &mut rtc::ctrl::W,
+{
+    write_enabled(rtc, || rtc.ctrl.modify(|_, w| f(w)));
+}
+
+pub struct Rtc {
+    rtc: RTC,
+}
+
+impl Rtc {
+    /// Takes the RTC, which needs the X32K oscillator enabled with
+    /// `Clocks::enable`. The counter is left running or stopped as it was.
+    pub fn new(rtc: RTC, clocks: &Clocks) -> Result<Self, (RTC, Error)> {
+        if !clocks.is_ready(ClkSrc::X32k) {
+            return Err((rtc, Error::X32kNotReady));
+        }
+        Ok(Self { rtc })
+    }
+
+    /// Releases the RTC resource, the counter keeps running.
+    pub fn free(self) -> RTC {
+        self.rtc
+    }
+
+    /// Starts the counter.
+    pub fn start(&mut self) {
+        update_ctrl(&self.rtc, |w| w.rtce().en());
+    }
+
+    /// Stops the counter.
+    pub fn stop(&mut self) {
+        update_ctrl(&self.rtc, |w| w.rtce().dis());
+    }
+
+    /// Checks if the counter is running.
+    pub fn is_running(&self) -> bool {
+        self.rtc.ctrl.read().rtce().is_en()
+    }
+
+    /// Current sub-second count.
+    pub(crate) fn sub_seconds(&self) -> u8 {
+        self.rtc.ssec.read().rtss().bits()
+    }
+
+    /// Reads the seconds and sub-seconds, retrying if the seconds rolled
+    /// over in between.
+    pub fn now(&self) -> Timestamp {
+        loop {
+            let seconds = self.rtc.sec.read().bits();
+            let sub_seconds = self.sub_seconds();
+            if self.rtc.sec.read().bits() == seconds {
+                return Timestamp {
+                    seconds,
+                    sub_seconds,
+                };
+            }
+        }
+    }
+
+    /// Sets the count, restarting the current second.
+    pub fn set_time(&mut self, seconds: u32) {
+        let running = self.is_running();
+        // The counters can only be written while stopped
+        self.stop();
+        write_enabled(&self.rtc, || {
+            self.rtc.sec.write(|w| unsafe { w.bits(seconds) });
+            wait_busy(&self.rtc);
+            self.rtc.ssec.write(|w| unsafe { w.rtss().bits(0) });
+        });
+        if running {
+            self.start();
+        }
+    }
+
+    /// Current calendar date-time.
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_timestamp(self.now().seconds)
+    }
+
+    /// Sets the calendar date-time, `None` if it's invalid or out of range.
+    pub fn set_date_time(&mut self, date_time: &DateTime) -> Option<()> {
+        self.set_time(date_time.timestamp()?);
+        Some(())
+    }
+
+    /// Sets the time-of-day alarm to go off at `seconds`.
+    ///
+    /// Only the low 20 bits of the count are compared, so the alarm must be
+    /// less than about 12 days ahead.
+    pub fn set_alarm(&mut self, seconds: u32) {
+        let listening = self.is_listening(Alarm::TimeOfDay);
+        // RAS may only be changed while the alarm is disabled
+        self.unlisten(Alarm::TimeOfDay);
+        self.rtc.ras.write(|w| unsafe { w.ras().bits(seconds) });
+        wait_busy(&self.rtc);
+        if listening {
+            self.listen(Alarm::TimeOfDay);
+        }
+    }
+
+    /// Sets the sub-second alarm to go off every `ticks` 1/256 seconds,
+    /// counting from when it's enabled with [`Rtc::listen`].
+    pub fn set_sub_second_alarm(&mut self, ticks: u32) {
+        let listening = self.is_listening(Alarm::SubSecond);
+        // RSSA counts up from the reload value and alarms on rolling over
+        self.unlisten(Alarm::SubSecond);
+        self.rtc
+            .rssa
+            .write(|w| unsafe { w.rssa().bits(0u32.wrapping_sub(ticks.max(1))) });
+        wait_busy(&self.rtc);
+        if listening {
+            self.listen(Alarm::SubSecond);
+        }
+    }
+
+    /// Enables an alarm and its interrupt. The RTC interrupt must also be
+    /// unmasked in the NVIC, see [`Rtc::unmask`].
+    pub fn listen(&mut self, alarm: Alarm) {
+        match alarm {
+            Alarm::TimeOfDay => update_ctrl(&self.rtc, |w| w.ade().en()),
+            Alarm::SubSecond => update_ctrl(&self.rtc, |w| w.ase().en()),
+        }
+    }
+
+    /// Disables an alarm and its interrupt.
+    pub fn unlisten(&mut self, alarm: Alarm) {
+        match alarm {
+            Alarm::TimeOfDay => update_ctrl(&self.rtc, |w| w.ade().dis()),
+            Alarm::SubSecond => update_ctrl(&self.rtc, |w| w.ase().dis()),
+        }
+    }
+
+    /// Checks if an alarm is enabled.
+    pub fn is_listening(&self, alarm: Alarm) -> bool {
+        let ctrl = self.rtc.ctrl.read();
+        match alarm {
+            Alarm::TimeOfDay => ctrl.ade().is_en(),
+            Alarm::SubSecond => ctrl.ase().is_en(),
+        }
+    }
+
+    /// Checks if an alarm went off.
+    pub fn is_pending(&self, alarm: Alarm) -> bool {
+        let ctrl = self.rtc.ctrl.read();
+        match alarm {
+            Alarm::TimeOfDay => ctrl.aldf().bit_is_set(),
+            Alarm::SubSecond => ctrl.alsf().bit_is_set(),
+        }
+    }
+
+    /// Clears an alarm flag.
+    pub fn clear_alarm(&mut self, alarm: Alarm) {
+        let flag = match alarm {
+            Alarm::TimeOfDay => ALDF,
+            Alarm::SubSecond => ALSF,
+        };
+        wait_busy(&self.rtc);
+        // Flags are cleared by writing 0, writing 1 leaves them unchanged
+        self.rtc
+            .ctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() & !flag) });
+        wait_busy(&self.rtc);
+    }
+
+    /// Outputs a square wave on the SQW pin.
+    pub fn enable_square_wave<A: AltMode, IO>(
+        &mut self,
+        pin: Pin<A, IO, RTC_SQW>,
+        wave: SquareWave,
+    ) -> Pin<AF3, Output<PushPull>, RTC_SQW>
+    where
+        Pin<AF3, IO, RTC_SQW>: AltFn,
+    {
+        let pin = pin.into_mode::<AF3>().into_push_pull_output(Level::Low);
+        match wave {
+            // The crystal clock bypasses the square-wave divider
+            SquareWave::Hz32768 => {
+                update_ctrl(&self.rtc, |w| w.sqe().inactive());
+                self.rtc.oscctrl.modify(|_, w| w.out32k().set_bit());
+            }
+            _ => {
+                self.rtc.oscctrl.modify(|_, w| w.out32k().clear_bit());
+                update_ctrl(&self.rtc, |w| {
+                    match wave {
+                        SquareWave::Hz1 => w.ft().freq1hz(),
+                        SquareWave::Hz512 => w.ft().freq512hz(),
+                        _ => w.ft().freq4khz(),
+                    };
+                    w.sqe().pending()
+                });
+            }
+        }
+        pin
+    }
+
+    /// Stops the square-wave output.
+    pub fn disable_square_wave(&mut self) {
+        self.rtc.oscctrl.modify(|_, w| w.out32k().clear_bit());
+        update_ctrl(&self.rtc, |w| w.sqe().inactive());
+    }
+
+    /// Trim correction (in ppm), positive values speed up the compensated
+    /// clock.
+    pub fn trim(&self) -> i8 {
+        self.rtc.trim.read().trim().bits() as i8
+    }
+
+    /// Sets the trim correction (in ppm), clamped to +/-127ppm.
+    pub fn set_trim(&mut self, ppm: i32) {
+        let trim = ppm.clamp(-MAX_TRIM_PPM, MAX_TRIM_PPM) as i8;
+        write_enabled(&self.rtc, || {
+            self.rtc.trim.modify(|_, w| unsafe { w.trim().bits(trim as u8) })
+        });
+    }
+
+    /// Corrects a crystal measured `error_ppm` fast (positive) or slow
+    /// (negative), see [`ppm_error`].
+    pub fn correct_ppm(&mut self, error_ppm: i32) {
+        self.set_trim(-error_ppm);
+    }
+
+    /// Enables the RTC interrupt in the NVIC.
+    pub fn unmask(&mut self) {
+        unsafe { NVIC::unmask(Interrupt::RTC) };
+    }
+
+    /// Disables the RTC interrupt in the NVIC.
+    pub fn mask(&mut self) {
+        NVIC::mask(Interrupt::RTC);
+    }
+}
@@ -0,0 +1,890 @@
+//! UART0 and UART1 serial ports implementing the embedded_hal serial traits.
+//!
+//! The baud rate clock is PCLK. Received characters narrower than 8 bits are
+//! returned right aligned, characters written are truncated to the data width.
+//!
+//! A port can be [split](Serial::split) into [`Tx`] and [`Rx`] halves, e.g. to
+//! receive from an interrupt handler while transmitting from the main loop,
+//! and [joined](Serial::join) again to free it.
+//!
+//! For interrupt-driven operation [`Serial::into_buffered`] moves the FIFOs
+//! into `heapless` ring buffers, see [`Buffered`].
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::Deref;
+
+use crate::clocks::{gate_peripheral, Clocks, PeripheralClock, PeripheralReset};
+use crate::gpio::{AltFn, AltMode, Input, Level, Output, Pin, PullUp, PushPull, AF2};
+use crate::pac::{uart0, Interrupt, UART0, UART1};
+use cortex_m::interrupt;
+use cortex_m::peripheral::NVIC;
+use embedded_hal::blocking::serial::write;
+use embedded_hal::serial;
+use heapless::spsc::{Consumer, Producer, Queue};
+
+/// UART0 Pins
+pub const UART0_TX: u8 = 4;
+pub const UART0_RX: u8 = 5;
+pub const UART0_CTS: u8 = 6;
+pub const UART0_RTS: u8 = 7;
+
+/// UART1 Pins
+pub const UART1_TX: u8 = 10;
+pub const UART1_RX: u8 = 11;
+pub const UART1_CTS: u8 = 12;
+pub const UART1_RTS: u8 = 13;
+
+/// RX FIFO depth in characters.
+const FIFO_DEPTH: u8 = 8;
+
+/// Serial errors, reported by the read following the error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The RX FIFO was full and a character was lost.
+    Overrun,
+    /// A character was received without a valid stop bit.
+    Framing,
+    /// A character was received with the wrong parity.
+    Parity,
+    /// The baud rate can't be generated from PCLK.
+    BaudRate,
+}
+
+/// UART interrupt events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The RX FIFO holds at least the threshold number of characters.
+    RxThreshold,
+    /// The RX FIFO holds characters and the line has been idle for the
+    /// timeout number of character times.
+    RxTimeout,
+    /// An overrun, framing or parity error occurred.
+    RxError,
+    /// The TX FIFO is down to its last character.
+    TxAlmostEmpty,
+}
+
+/// Number of data bits per character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+/// Parity bit appended to each character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+    /// Parity bit always 1.
+    Mark,
+    /// Parity bit always 0.
+    Space,
+}
+
+/// Number of stop bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// 1.5 stop bits with 5 data bits, otherwise 2 stop bits.
+    Two,
+}
+
+/// Serial port configuration, 115200 baud 8N1 by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    baudrate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            baudrate: 115_200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl Config {
+    /// Selects the baud rate in bits per second.
+    pub fn baudrate(mut self, baudrate: u32) -> Self {
+        self.baudrate = baudrate;
+        self
+    }
+
+    /// Selects the number of data bits.
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+
+    /// Selects the parity.
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    /// Selects the number of stop bits.
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+}
+
+/// Baud rate generator settings: `PCLK / (factor * (ibaud + dbaud / 128))`.
+struct BaudDivisor {
+    factor: u8,
+    ibaud: u16,
+    dbaud: u16,
+}
+
+/// Picks the smallest oversampling factor whose divisor fits, for the
+/// finest fractional resolution.
+fn baud_divisor(pclk: u32, baudrate: u32) -> Option<BaudDivisor> {
+    // FACTOR field values 3..0 select oversampling by 16, 32, 64 and 128
+    (0..4u8).rev().find_map(|factor| {
+        let den = (128u64 >> factor) * baudrate.max(1) as u64;
+        let div = (pclk as u64 * 128 + den / 2) / den;
+        let ibaud = div >> 7;
+        if (1..=0xFFF).contains(&ibaud) {
+            Some(BaudDivisor {
+                factor,
+                ibaud: ibaud as u16,
+                dbaud: (div & 0x7F) as u16,
+            })
+        } else {
+            None
+        }
+    })
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// UART peripheral instances.
+pub trait Instance:
+    Deref<Target = uart0::RegisterBlock> + PeripheralClock + PeripheralReset + sealed::Sealed
+{
+    /// NVIC interrupt raised by this UART.
+    const INTERRUPT: Interrupt;
+    #[doc(hidden)]
+    const PTR: *const uart0::RegisterBlock;
+}
+
+/// Register block of a UART owned by a split or buffered port.
+fn block<UART: Instance>() -> &'static uart0::RegisterBlock {
+    unsafe { &*UART::PTR }
+}
+
+macro_rules! uarts {
+    ([$(($UART: ident),)+]) => {
+        $(
+            impl sealed::Sealed for $UART {}
+            impl Instance for $UART {
+                const INTERRUPT: Interrupt = Interrupt::$UART;
+                const PTR: *const uart0::RegisterBlock = $UART::PTR;
+            }
+        )+
+    };
+}
+
+uarts!([(UART0), (UART1),]);
+
+pub struct Pins<AF: AltMode, const TX_IDX: u8, const RX_IDX: u8> {
+    pub tx: Pin<AF, Output<PushPull>, TX_IDX>,
+    pub rx: Pin<AF, Input<PullUp>, RX_IDX>,
+}
+
+/// Hardware flow control pins. CTS and RTS are active low.
+pub struct FlowPins<AF: AltMode, const CTS_IDX: u8, const RTS_IDX: u8> {
+    pub cts: Pin<AF, Input<PullUp>, CTS_IDX>,
+    pub rts: Pin<AF, Output<PushPull>, RTS_IDX>,
+}
+
+/// Serial port, with hardware flow control if `FLOW` is [`FlowPins`].
+pub struct Serial<UART, AF: AltMode, const TX_IDX: u8, const RX_IDX: u8, FLOW = ()> {
+    uart: UART,
+    pins: Pins<AF, TX_IDX, RX_IDX>,
+    flow: FLOW,
+}
+
+/// Transmitting half of a split serial port, which also holds the UART and
+/// the flow control pins.
+pub struct Tx<UART, AF: AltMode, const TX_IDX: u8, FLOW = ()> {
+    uart: UART,
+    pin: Pin<AF, Output<PushPull>, TX_IDX>,
+    flow: FLOW,
+}
+
+/// Receiving half of a split serial port.
+pub struct Rx<UART, AF: AltMode, const RX_IDX: u8> {
+    pin: Pin<AF, Input<PullUp>, RX_IDX>,
+    _uart: PhantomData<UART>,
+}
+
+impl<UART: Instance, AF: AltMode, const TX_IDX: u8, const RX_IDX: u8, FLOW>
+    Serial<UART, AF, TX_IDX, RX_IDX, FLOW>
+{
+    fn configure(
+        uart: UART,
+        pins: Pins<AF, TX_IDX, RX_IDX>,
+        flow: FLOW,
+        config: Config,
+        div: BaudDivisor,
+        clocks: &Clocks,
+    ) -> Self {
+        clocks.enable_peripheral::<UART>();
+        clocks.reset_peripheral::<UART>();
+        let mut serial = Self { uart, pins, flow };
+        serial.write_baud(div);
+        let ctrl = &serial.uart.ctrl;
+        ctrl.modify(|_, w| w.clksel().system().bitacc().frame());
+        match config.data_bits {
+            DataBits::Five => ctrl.modify(|_, w| w.char_size()._5()),
+            DataBits::Six => ctrl.modify(|_, w| w.char_size()._6()),
+            DataBits::Seven => ctrl.modify(|_, w| w.char_size()._7()),
+            DataBits::Eight => ctrl.modify(|_, w| w.char_size()._8()),
+        }
+        match config.parity {
+            Parity::None => ctrl.modify(|_, w| w.parity_en().dis()),
+            Parity::Even => ctrl.modify(|_, w| w.parity_en().en().parity().even()),
+            Parity::Odd => ctrl.modify(|_, w| w.parity_en().en().parity().odd()),
+            Parity::Mark => ctrl.modify(|_, w| w.parity_en().en().parity().mark()),
+            Parity::Space => ctrl.modify(|_, w| w.parity_en().en().parity().space()),
+        }
+        match config.stop_bits {
+            StopBits::One => ctrl.modify(|_, w| w.stopbits()._1()),
+            StopBits::Two => ctrl.modify(|_, w| w.stopbits()._1_5()),
+        }
+        ctrl.modify(|_, w| w.enable().en());
+        serial.clear_fifos();
+        serial
+    }
+
+    /// Changes the baud rate, e.g. after `Clocks::set_ovr` changed PCLK.
+    /// The baud rate is left unchanged if it can't be generated from PCLK,
+    /// e.g. 115200 baud from the nano-ring or X32K clock.
+    pub fn set_baudrate(&mut self, baudrate: u32, clocks: &Clocks) -> Result<(), Error> {
+        let div = baud_divisor(clocks.pclk(), baudrate).ok_or(Error::BaudRate)?;
+        self.write_baud(div);
+        Ok(())
+    }
+
+    fn write_baud(&mut self, div: BaudDivisor) {
+        self.uart
+            .baud0
+            .write(|w| unsafe { w.ibaud().bits(div.ibaud).factor().bits(div.factor) });
+        self.uart.baud1.write(|w| unsafe { w.dbaud().bits(div.dbaud) });
+    }
+
+    /// Flushes the TX and RX FIFOs, dropping any characters in them.
+    pub fn clear_fifos(&mut self) {
+        self.uart
+            .ctrl
+            .modify(|_, w| w.tx_flush().set_bit().rx_flush().set_bit());
+    }
+
+    /// Number of characters in the RX FIFO which raises [`Event::RxThreshold`],
+    /// 1 to 8.
+    pub fn set_rx_threshold(&mut self, chars: u8) {
+        let chars = chars.clamp(1, FIFO_DEPTH);
+        self.uart
+            .thresh_ctrl
+            .modify(|_, w| unsafe { w.rx_fifo_thresh().bits(chars) });
+    }
+
+    /// Number of idle character times which raises [`Event::RxTimeout`].
+    pub fn set_rx_timeout(&mut self, chars: u8) {
+        self.uart.ctrl.modify(|_, w| unsafe { w.rx_to().bits(chars) });
+    }
+
+    /// Enables an interrupt event. The UART interrupt must also be unmasked
+    /// in the NVIC, see [`Serial::unmask`].
+    pub fn listen(&mut self, event: Event) {
+        set_event(&self.uart, event, true);
+    }
+
+    /// Disables an interrupt event.
+    pub fn unlisten(&mut self, event: Event) {
+        set_event(&self.uart, event, false);
+    }
+
+    /// Checks if an event is flagged.
+    pub fn is_pending(&self, event: Event) -> bool {
+        let int_fl = self.uart.int_fl.read();
+        match event {
+            Event::RxThreshold => int_fl.rx_fifo_thresh().bit_is_set(),
+            Event::RxTimeout => int_fl.rx_timeout().bit_is_set(),
+            Event::RxError => {
+                int_fl.rx_overrun().bit_is_set()
+                    || int_fl.rx_frame_error().bit_is_set()
+                    || int_fl.rx_parity_error().bit_is_set()
+            }
+            Event::TxAlmostEmpty => int_fl.tx_fifo_almost_empty().bit_is_set(),
+        }
+    }
+
+    /// Clears an event flag. Error flags are also cleared by the read
+    /// reporting the error.
+    pub fn clear_event(&mut self, event: Event) {
+        clear_event(&self.uart, event);
+    }
+
+    /// Enables the UART interrupt in the NVIC.
+    pub fn unmask(&mut self) {
+        unsafe { NVIC::unmask(UART::INTERRUPT) };
+    }
+
+    /// Disables the UART interrupt in the NVIC.
+    pub fn mask(&mut self) {
+        NVIC::mask(UART::INTERRUPT);
+    }
+
+    /// Switches to interrupt-driven operation backed by `rx` and `tx`.
+    ///
+    /// Received characters are moved into `rx` whenever `rx_threshold`
+    /// characters are waiting, and once the line has been idle for
+    /// `rx_timeout` character times, which also marks the end of a frame.
+    /// The returned [`Buffered`] must be serviced from the UART interrupt
+    /// handler. The interrupt is left masked, call [`Buffered::unmask`] once
+    /// the handler can reach it.
+    pub fn into_buffered<'a, const RX_N: usize, const TX_N: usize>(
+        mut self,
+        rx: &'a mut Queue<Received, RX_N>,
+        tx: &'a mut Queue<u8, TX_N>,
+        rx_threshold: u8,
+        rx_timeout: u8,
+    ) -> (
+        Buffered<'a, UART, AF, TX_IDX, RX_IDX, FLOW, RX_N, TX_N>,
+        BufferedTx<'a, UART, TX_N>,
+        BufferedRx<'a, UART, RX_N>,
+    ) {
+        let (rx_producer, rx_consumer) = rx.split();
+        let (tx_producer, tx_consumer) = tx.split();
+        self.set_rx_threshold(rx_threshold);
+        self.set_rx_timeout(rx_timeout.max(1));
+        self.listen(Event::RxThreshold);
+        self.listen(Event::RxTimeout);
+        self.listen(Event::RxError);
+        (
+            Buffered {
+                serial: self,
+                rx: rx_producer,
+                tx: tx_consumer,
+                lost: false,
+            },
+            BufferedTx {
+                queue: tx_producer,
+                _uart: PhantomData,
+            },
+            BufferedRx {
+                queue: rx_consumer,
+                frame_len: 0,
+                _uart: PhantomData,
+            },
+        )
+    }
+
+    /// Disables the UART, gates its clock and releases the UART, pins and
+    /// flow control pins.
+    pub fn free(self) -> (UART, Pins<AF, TX_IDX, RX_IDX>, FLOW) {
+        self.uart.ctrl.modify(|_, w| w.enable().dis());
+        gate_peripheral::<UART>();
+        (self.uart, self.pins, self.flow)
+    }
+
+    /// Splits the port into independent transmit and receive halves.
+    pub fn split(self) -> (Tx<UART, AF, TX_IDX, FLOW>, Rx<UART, AF, RX_IDX>) {
+        (
+            Tx {
+                uart: self.uart,
+                pin: self.pins.tx,
+                flow: self.flow,
+            },
+            Rx {
+                pin: self.pins.rx,
+                _uart: PhantomData,
+            },
+        )
+    }
+
+    /// Joins halves from [`Serial::split`] back into a serial port.
+    pub fn join(tx: Tx<UART, AF, TX_IDX, FLOW>, rx: Rx<UART, AF, RX_IDX>) -> Self {
+        Self {
+            uart: tx.uart,
+            pins: Pins {
+                tx: tx.pin,
+                rx: rx.pin,
+            },
+            flow: tx.flow,
+        }
+    }
+}
+
+/// Interrupt enables may be changed from both the buffered halves and the
+/// interrupt handler.
+fn set_event(uart: &uart0::RegisterBlock, event: Event, enable: bool) {
+    interrupt::free(|_| match event {
+        Event::RxThreshold => uart.int_en.modify(|_, w| w.rx_fifo_thresh().bit(enable)),
+        Event::RxTimeout => uart.int_en.modify(|_, w| w.rx_timeout().bit(enable)),
+        Event::RxError => uart.int_en.modify(|_, w| {
+            w.rx_overrun()
+                .bit(enable)
+                .rx_frame_error()
+                .bit(enable)
+                .rx_parity_error()
+                .bit(enable)
+        }),
+        Event::TxAlmostEmpty => uart
+            .int_en
+            .modify(|_, w| w.tx_fifo_almost_empty().bit(enable)),
+    });
+}
+
+fn clear_event(uart: &uart0::RegisterBlock, event: Event) {
+    match event {
+        Event::RxThreshold => uart.int_fl.write(|w| w.rx_fifo_thresh().set_bit()),
+        Event::RxTimeout => uart.int_fl.write(|w| w.rx_timeout().set_bit()),
+        Event::RxError => uart.int_fl.write(|w| {
+            w.rx_overrun()
+                .set_bit()
+                .rx_frame_error()
+                .set_bit()
+                .rx_parity_error()
+                .set_bit()
+        }),
+        Event::TxAlmostEmpty => uart.int_fl.write(|w| w.tx_fifo_almost_empty().set_bit()),
+    }
+}
+
+fn read(uart: &uart0::RegisterBlock) -> nb::Result<u8, Error> {
+    let int_fl = uart.int_fl.read();
+    let error = if int_fl.rx_overrun().bit_is_set() {
+        Some(Error::Overrun)
+    } else if int_fl.rx_frame_error().bit_is_set() {
+        Some(Error::Framing)
+    } else if int_fl.rx_parity_error().bit_is_set() {
+        Some(Error::Parity)
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        clear_event(uart, Event::RxError);
+        return Err(nb::Error::Other(error));
+    }
+    if uart.status.read().rx_empty().bit_is_set() {
+        Err(nb::Error::WouldBlock)
+    } else {
+        Ok(uart.fifo.read().fifo().bits())
+    }
+}
+
+fn write(uart: &uart0::RegisterBlock, word: u8) -> nb::Result<(), Error> {
+    if uart.status.read().tx_full().bit_is_set() {
+        Err(nb::Error::WouldBlock)
+    } else {
+        uart.fifo.write(|w| unsafe { w.fifo().bits(word) });
+        Ok(())
+    }
+}
+
+/// Waits until the last character has left the shift register.
+fn flush(uart: &uart0::RegisterBlock) -> nb::Result<(), Error> {
+    let status = uart.status.read();
+    if status.tx_empty().bit_is_set() && status.tx_busy().bit_is_clear() {
+        Ok(())
+    } else {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+impl<UART: Instance, AF: AltMode, const TX_IDX: u8, const RX_IDX: u8, FLOW> serial::Read<u8>
+    for Serial<UART, AF, TX_IDX, RX_IDX, FLOW>
+{
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        read(&self.uart)
+    }
+}
+
+impl<UART: Instance, AF: AltMode, const TX_IDX: u8, const RX_IDX: u8, FLOW> serial::Write<u8>
+    for Serial<UART, AF, TX_IDX, RX_IDX, FLOW>
+{
+    type Error = Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+        write(&self.uart, word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        flush(&self.uart)
+    }
+}
+
+impl<UART: Instance, AF: AltMode, const TX_IDX: u8, const RX_IDX: u8, FLOW> write::Default<u8>
+    for Serial<UART, AF, TX_IDX, RX_IDX, FLOW>
+{
+}
+
+impl<UART: Instance, AF: AltMode, const TX_IDX: u8, const RX_IDX: u8, FLOW> fmt::Write
+    for Serial<UART, AF, TX_IDX, RX_IDX, FLOW>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        use embedded_hal::blocking::serial::Write;
+        self.bwrite_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl<UART: Instance, AF: AltMode, const RX_IDX: u8> serial::Read<u8> for Rx<UART, AF, RX_IDX> {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        read(block::<UART>())
+    }
+}
+
+impl<UART: Instance, AF: AltMode, const TX_IDX: u8, FLOW> serial::Write<u8>
+    for Tx<UART, AF, TX_IDX, FLOW>
+{
+    type Error = Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+        write(&self.uart, word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        flush(&self.uart)
+    }
+}
+
+impl<UART: Instance, AF: AltMode, const TX_IDX: u8, FLOW> write::Default<u8>
+    for Tx<UART, AF, TX_IDX, FLOW>
+{
+}
+
+impl<UART: Instance, AF: AltMode, const TX_IDX: u8, FLOW> fmt::Write for Tx<UART, AF, TX_IDX, FLOW> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        use embedded_hal::blocking::serial::Write;
+        self.bwrite_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Entries in the receive ring buffer of a [`Buffered`] serial port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Received {
+    Char(u8),
+    /// Error reported in place of the characters it affected.
+    Error(Error),
+    /// The line went idle, ending a frame.
+    Idle,
+}
+
+/// Interrupt side of a buffered serial port, holding the serial port.
+///
+/// ```ignore
+/// let (mut buffered, tx, rx) = serial.into_buffered(rx_queue, tx_queue, 8, 4);
+/// interrupt::free(|cs| {
+///     buffered.unmask();
+///     BUFFERED.borrow(cs).replace(Some(buffered));
+/// });
+///
+/// #[interrupt]
+/// fn UART0() {
+///     // BUFFERED is a Mutex<RefCell<Option<Buffered<..>>>>
+///     interrupt::free(|cs| BUFFERED.borrow(cs).borrow_mut().as_mut().unwrap().on_interrupt());
+/// }
+/// ```
+pub struct Buffered<
+    'a,
+    UART,
+    AF: AltMode,
+    const TX_IDX: u8,
+    const RX_IDX: u8,
+    FLOW,
+    const RX_N: usize,
+    const TX_N: usize,
+> {
+    serial: Serial<UART, AF, TX_IDX, RX_IDX, FLOW>,
+    rx: Producer<'a, Received, RX_N>,
+    tx: Consumer<'a, u8, TX_N>,
+    lost: bool,
+}
+
+impl<
+        'a,
+        UART: Instance,
+        AF: AltMode,
+        const TX_IDX: u8,
+        const RX_IDX: u8,
+        FLOW,
+        const RX_N: usize,
+        const TX_N: usize,
+    > Buffered<'a, UART, AF, TX_IDX, RX_IDX, FLOW, RX_N, TX_N>
+{
+    /// Services the UART, to be called from its interrupt handler.
+    pub fn on_interrupt(&mut self) {
+        let uart = block::<UART>();
+        let idle = uart.int_fl.read().rx_timeout().bit_is_set();
+        clear_event(uart, Event::RxThreshold);
+        clear_event(uart, Event::RxTimeout);
+        // A character is left in the FIFO until the line goes idle, the RX
+        // timeout only runs while the FIFO is not empty.
+        let keep = if idle { 0 } else { 1 };
+        while uart.status.read().rx_fifo_cnt().bits() > keep {
+            let received = match read(uart) {
+                Ok(char) => Received::Char(char),
+                Err(nb::Error::Other(error)) => Received::Error(error),
+                Err(nb::Error::WouldBlock) => break,
+            };
+            self.push(received);
+        }
+        if idle {
+            self.push(Received::Idle);
+        }
+
+        clear_event(uart, Event::TxAlmostEmpty);
+        while uart.status.read().tx_full().bit_is_clear() {
+            match self.tx.dequeue() {
+                Some(char) => uart.fifo.write(|w| unsafe { w.fifo().bits(char) }),
+                None => {
+                    set_event(uart, Event::TxAlmostEmpty, false);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Queues a received entry, reporting dropped entries as an overrun once
+    /// there is room again.
+    fn push(&mut self, received: Received) {
+        if self.lost {
+            if self.rx.enqueue(Received::Error(Error::Overrun)).is_err() {
+                return;
+            }
+            self.lost = false;
+        }
+        if self.rx.enqueue(received).is_err() {
+            self.lost = true;
+        }
+    }
+
+    /// Enables the UART interrupt in the NVIC. Call this once the handler
+    /// can reach the `Buffered`, it is serviced from then on.
+    pub fn unmask(&mut self) {
+        self.serial.unmask();
+    }
+
+    /// Stops interrupt-driven operation and returns the serial port.
+    pub fn free(mut self) -> Serial<UART, AF, TX_IDX, RX_IDX, FLOW> {
+        self.serial.mask();
+        for event in [
+            Event::RxThreshold,
+            Event::RxTimeout,
+            Event::RxError,
+            Event::TxAlmostEmpty,
+        ] {
+            self.serial.unlisten(event);
+        }
+        self.serial
+    }
+}
+
+/// Transmitting half of a buffered serial port.
+pub struct BufferedTx<'a, UART, const TX_N: usize> {
+    queue: Producer<'a, u8, TX_N>,
+    _uart: PhantomData<UART>,
+}
+
+impl<'a, UART: Instance, const TX_N: usize> serial::Write<u8> for BufferedTx<'a, UART, TX_N> {
+    type Error = Error;
+
+    /// Queues a character, blocking only while the ring buffer is full.
+    fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+        self.queue.enqueue(word).map_err(|_| nb::Error::WouldBlock)?;
+        // The almost empty flag is only raised by a draining FIFO, so pend
+        // the interrupt to start an idle transmitter.
+        set_event(block::<UART>(), Event::TxAlmostEmpty, true);
+        NVIC::pend(UART::INTERRUPT);
+        Ok(())
+    }
+
+    /// Waits until the ring buffer and the FIFO are empty.
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        if self.queue.len() != 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        flush(block::<UART>())
+    }
+}
+
+impl<'a, UART: Instance, const TX_N: usize> write::Default<u8> for BufferedTx<'a, UART, TX_N> {}
+
+impl<'a, UART: Instance, const TX_N: usize> fmt::Write for BufferedTx<'a, UART, TX_N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        use embedded_hal::blocking::serial::Write;
+        self.bwrite_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Receiving half of a buffered serial port.
+pub struct BufferedRx<'a, UART, const RX_N: usize> {
+    queue: Consumer<'a, Received, RX_N>,
+    frame_len: usize,
+    _uart: PhantomData<UART>,
+}
+
+impl<'a, UART: Instance, const RX_N: usize> BufferedRx<'a, UART, RX_N> {
+    /// Reads characters into `buf` until the line goes idle and returns the
+    /// frame length.
+    ///
+    /// Call again with the same buffer while `WouldBlock` is returned. An
+    /// error discards the partial frame; characters beyond the end of `buf`
+    /// are dropped and reported as an overrun at the end of the frame.
+    pub fn read_frame(&mut self, buf: &mut [u8]) -> nb::Result<usize, Error> {
+        while let Some(received) = self.queue.dequeue() {
+            match received {
+                Received::Char(char) => {
+                    if let Some(slot) = buf.get_mut(self.frame_len) {
+                        *slot = char;
+                    }
+                    self.frame_len += 1;
+                }
+                Received::Error(error) => {
+                    self.frame_len = 0;
+                    return Err(nb::Error::Other(error));
+                }
+                // Idle markers without characters come from a timeout
+                // following a threshold drain, no frame ended
+                Received::Idle if self.frame_len == 0 => {}
+                Received::Idle => {
+                    let len = core::mem::take(&mut self.frame_len);
+                    return if len > buf.len() {
+                        Err(nb::Error::Other(Error::Overrun))
+                    } else {
+                        Ok(len)
+                    };
+                }
+            }
+        }
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+impl<'a, UART: Instance, const RX_N: usize> serial::Read<u8> for BufferedRx<'a, UART, RX_N> {
+    type Error = Error;
+
+    /// Reads the next character, skipping idle markers.
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        loop {
+            match self.queue.dequeue() {
+                Some(Received::Char(char)) => return Ok(char),
+                Some(Received::Error(error)) => return Err(nb::Error::Other(error)),
+                Some(Received::Idle) => {}
+                None => return Err(nb::Error::WouldBlock),
+            }
+        }
+    }
+}
+
+macro_rules! serial_ports {
+    (
+        [$(($new: ident, $new_flow: ident, $UART: ident, $AF: ident, $tx: expr, $rx: expr, $cts: expr, $rts: expr),)+]
+    ) => {
+        $(
+            impl Serial<$UART, $AF, $tx, $rx> {
+                /// Claims the TX and RX pins and configures the UART. If the
+                /// baud rate can't be generated from PCLK the UART and the
+                /// untouched pins are handed back with [`Error::BaudRate`].
+                #[allow(clippy::type_complexity)]
+                pub fn $new<TA: AltMode, RA: AltMode, TM, RM>(
+                    uart: $UART,
+                    tx: Pin::<TA, TM, $tx>,
+                    rx: Pin::<RA, RM, $rx>,
+                    config: Config,
+                    clocks: &Clocks
+                ) -> Result<Self, ($UART, (Pin<TA, TM, $tx>, Pin<RA, RM, $rx>), Error)>
+                where
+                    Pin<$AF, TM, $tx>: AltFn,
+                    Pin<$AF, RM, $rx>: AltFn,
+                {
+                    let div = match baud_divisor(clocks.pclk(), config.baudrate) {
+                        Some(div) => div,
+                        None => return Err((uart, (tx, rx), Error::BaudRate)),
+                    };
+                    // TX idles high, hold it there while the UART is set up
+                    let pins = Pins {
+                        tx: tx.into_mode::<$AF>().into_push_pull_output(Level::High),
+                        rx: rx.into_mode::<$AF>().into_pullup_input(),
+                    };
+                    Ok(Self::configure(uart, pins, (), config, div, clocks))
+                }
+            }
+
+            impl Serial<$UART, $AF, $tx, $rx, FlowPins<$AF, $cts, $rts>> {
+                /// Claims the TX, RX, CTS and RTS pins and configures the UART
+                /// with hardware flow control. RTS is deasserted while the RX
+                /// FIFO is nearly full, nothing is sent while CTS is deasserted.
+                /// Fails like the constructor without flow control.
+                #[allow(clippy::too_many_arguments, clippy::type_complexity)]
+                pub fn $new_flow<TA: AltMode, RA: AltMode, CA: AltMode, SA: AltMode, TM, RM, CM, SM>(
+                    uart: $UART,
+                    tx: Pin::<TA, TM, $tx>,
+                    rx: Pin::<RA, RM, $rx>,
+                    cts: Pin::<CA, CM, $cts>,
+                    rts: Pin::<SA, SM, $rts>,
+                    config: Config,
+                    clocks: &Clocks
+                ) -> Result<
+                    Self,
+                    (
+                        $UART,
+                        (Pin<TA, TM, $tx>, Pin<RA, RM, $rx>, Pin<CA, CM, $cts>, Pin<SA, SM, $rts>),
+                        Error,
+                    ),
+                >
+                where
+                    Pin<$AF, TM, $tx>: AltFn,
+                    Pin<$AF, RM, $rx>: AltFn,
+                    Pin<$AF, CM, $cts>: AltFn,
+                    Pin<$AF, SM, $rts>: AltFn,
+                {
+                    let div = match baud_divisor(clocks.pclk(), config.baudrate) {
+                        Some(div) => div,
+                        None => return Err((uart, (tx, rx, cts, rts), Error::BaudRate)),
+                    };
+                    let pins = Pins {
+                        tx: tx.into_mode::<$AF>().into_push_pull_output(Level::High),
+                        rx: rx.into_mode::<$AF>().into_pullup_input(),
+                    };
+                    let flow = FlowPins {
+                        cts: cts.into_mode::<$AF>().into_pullup_input(),
+                        rts: rts.into_mode::<$AF>().into_push_pull_output(Level::High),
+                    };
+                    let serial = Self::configure(uart, pins, flow, config, div, clocks);
+                    // Leave room for the characters the sender has in flight
+                    serial
+                        .uart
+                        .thresh_ctrl
+                        .modify(|_, w| unsafe { w.rts_fifo_thresh().bits(FIFO_DEPTH - 2) });
+                    serial.uart.ctrl.modify(|_, w| w.flow_pol()._0().flow_ctrl().en());
+                    Ok(serial)
+                }
+            }
+        )+
+    };
+}
+
+serial_ports!([
+    (uart0, uart0_flow_control, UART0, AF2, UART0_TX, UART0_RX, UART0_CTS, UART0_RTS),
+    (uart1, uart1_flow_control, UART1, AF2, UART1_TX, UART1_RX, UART1_CTS, UART1_RTS),
+]);
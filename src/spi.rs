@@ -1,18 +1,15 @@
 //! SPI implementation of the embedded_hal spi traits and configuration
 //!
-use crate::clocks::PCLK_FREQ;
+use crate::clocks::{gate_peripheral, Clocks};
 use crate::gpio::{AltFn, AltMode, Floating, Gpio, Input, Level, Output, Pin, PushPull, AF1, AF2, DriveStrength};
 use core::marker::PhantomData;
-use cortex_m::asm::nop;
-use embedded_hal::digital::v2::OutputPin;
 use embedded_hal::spi::{FullDuplex, Mode, Phase, Polarity};
 
 use crate::pac::spi17y;
 //use crate::pac::spimss;
 use crate::pac::SPI17Y as SPI0;
-//use crate::pac::SPIMSS as SPI1;
+use crate::pac::SPIMSS as SPI1;
 
-use core::mem;
 use nb;
 use void::Void;
 
@@ -37,10 +34,10 @@ pub struct Pins<
     const MOSI_IDX: u8,
     const SS_IDX: u8,
 > {
-    sclk: Pin<AF, Output<PushPull>, SCLK_IDX>,
-    miso: Pin<AF, Input<Floating>, MISO_IDX>,
-    mosi: Pin<AF, Output<PushPull>, MOSI_IDX>,
-    ss: Option<Pin<AF, Output<PushPull>, SS_IDX>>,
+    pub sclk: Pin<AF, Output<PushPull>, SCLK_IDX>,
+    pub miso: Pin<AF, Input<Floating>, MISO_IDX>,
+    pub mosi: Pin<AF, Output<PushPull>, MOSI_IDX>,
+    pub ss: Option<Pin<AF, Output<PushPull>, SS_IDX>>,
 }
 
 pub struct SpiPort<
@@ -52,6 +49,7 @@ pub struct SpiPort<
     const SS_IDX: u8,
 > {
     pins: Pins<AF, SCLK_IDX, MISO_IDX, MOSI_IDX, SS_IDX>,
+    pclk: u32,
     _af: PhantomData<AF>,
     _port: PhantomData<PORT>,
 }
@@ -100,7 +98,7 @@ impl<
 
 macro_rules! spi_ports{
     (
-        [$(($new: ident, $AF: ident, $PORT: ident, $a: expr, $b: expr, $c: expr, $d: expr),)+]
+        [$(($new: ident, $AF: ident, $PORT: ident, $SPI: ident, $a: expr, $b: expr, $c: expr, $d: expr),)+]
     ) => {
         $(
             impl SpiPort<$AF, $PORT, $a, $b, $c, $d>{
@@ -108,11 +106,16 @@ macro_rules! spi_ports{
                     sclk: Pin::<SA, SM, $a>,
                     miso: Pin::<IA, IM, $b>,
                     mosi: Pin::<OA, OM, $c>,
-                    ss: Pin::<XA, XM, $d>
+                    ss: Pin::<XA, XM, $d>,
+                    clocks: &Clocks
                 ) -> Self {
+                    // Un-gate the SPI block and start from a clean state
+                    clocks.enable_peripheral::<$SPI>();
+                    clocks.reset_peripheral::<$SPI>();
                     let pins = Self::configure_pins(sclk, miso, mosi, ss);
                     Self{
-                        pins: pins,
+                        pins,
+                        pclk: clocks.pclk(),
                         _af: PhantomData,
                         _port: PhantomData,
                     }
@@ -123,8 +126,8 @@ macro_rules! spi_ports{
 }
 
 spi_ports!([
-    (new_spi_0, AF1, Spi0, SPI0_SCK, SPI0_MISO, SPI0_MOSI, SPI0_SS0),
-    (new_spi_1_af2, AF2, Spi1, 2, 0, 1, 3),
+    (new_spi_0, AF1, Spi0, SPI0, SPI0_SCK, SPI0_MISO, SPI0_MOSI, SPI0_SS0),
+    (new_spi_1_af2, AF2, Spi1, SPI1, 2, 0, 1, 3),
 ]);
 
 pub trait BurstWrite{
@@ -161,10 +164,21 @@ fn get_sclk_dividers(pclk_freq: u32, sclk_freq: u32) -> SclkDividers {
 
 pub type SpiPort0 = SpiPort<AF1, Spi0, SPI0_SCK, SPI0_MISO, SPI0_MOSI, SPI0_SS0>;
 
+pub type SpiPort1 = SpiPort<AF2, Spi1, 2, 0, 1, 3>;
+
+impl SpiPort1 {
+    /// Disables the SPI1 Port, gates its clock and releases the pins.
+    pub fn free(self) -> Pins<AF2, 2, 0, 1, 3> {
+        let spi = unsafe { &*SPI1::ptr() };
+        spi.ctrl.modify(|_, w| w.spien().clear_bit());
+        gate_peripheral::<SPI1>();
+        self.pins
+    }
+}
+
 impl SpiPort0 {
     fn block(&self) -> &spi17y::RegisterBlock {
-        let ptr = unsafe { &*SPI0::ptr() };
-        ptr
+        unsafe { &*SPI0::ptr() }
     }
 
     /// Configures the SPI0 Port polarity, mode
@@ -199,7 +213,7 @@ impl SpiPort0 {
             self.block().ctrl2.modify(|_, w| w.numbits().bits(8u8));
         }
         // Sclk frequency configuration
-        let sclk_divisors = get_sclk_dividers(PCLK_FREQ, sclk_freq);
+        let sclk_divisors = get_sclk_dividers(self.pclk, sclk_freq);
         unsafe {
             self.block()
                 .clk_cfg
@@ -283,6 +297,16 @@ impl SpiPort0 {
         self.block().ctrl0.modify(|_, w| w.en().dis());
     }
     
+    /// Disables the SPI0 Port, gates its clock and releases the pins.
+    ///
+    /// The SS pin is `None` if it was taken with [`SpiPort0::take_ss`].
+    pub fn free(mut self) -> Pins<AF1, SPI0_SCK, SPI0_MISO, SPI0_MOSI, SPI0_SS0> {
+        while self.is_busy() {}
+        self.disable();
+        gate_peripheral::<SPI0>();
+        self.pins
+    }
+
     /// Checks if transmit is still active.
     pub fn is_busy(&self) -> bool {
         self.block().stat.read().busy().is_active()
@@ -294,7 +318,7 @@ impl SpiPort0 {
         unsafe {
             self.block().ctrl0.modify(|r, w| w.bits(r.bits() & !(0x01 << 16)));
         }
-        let ss = mem::replace(&mut self.pins.ss, None).unwrap();
+        let ss = self.pins.ss.take().unwrap();
         ss.into_mode::<Gpio>()
     }
 
@@ -315,7 +339,7 @@ impl BurstWrite for SpiPort0{
         // Wait for Idle SPI controller
         while self.is_busy() {}
         self.clear_fifos();
-        let burst_len = data.len() as usize;
+        let burst_len = data.len();
         self.block().ctrl1.modify(|_, w| unsafe{w.tx_num_char().bits(burst_len as u16)});
         
         
@@ -0,0 +1,413 @@
+//! Key/value settings store on top of the internal flash.
+//!
+//! Records are appended to a log kept in one of two or more reserved pages,
+//! so updating a setting does not erase flash. Each record holds a 16-bit
+//! key, the value and a CRC-32. A record cut short by a power loss fails its
+//! CRC and is skipped, and the latest valid record of a key wins.
+//!
+//! When the active page is full its live records are copied to the next
+//! page, which only becomes active once its header is written last. A power
+//! loss during this compaction leaves the old page active. The reserved
+//! pages are used in turn, spreading the erases over all of them.
+//!
+//! A page is retired by programming its magic word to 0 before it's erased,
+//! and once the page replacing it is active. An interrupted erase can leave
+//! random bits in a page, so mounting only trusts pages that still hold the
+//! magic word.
+//!
+//! The latest record of each key in the active page is indexed in RAM, so
+//! lookups do not scan the flash. The index is built in a single pass when
+//! the store is mounted and holds up to `KEYS` keys.
+//!
+//! A page starts with its sequence number and a magic word, 0 once the page
+//! is retired. A record starts
+//! with its key and length word and its CRC word, followed by the value
+//! padded to a whole word with 0xFF.
+use crate::flash::{self, Flash, FLASH_BASE, FLASH_SIZE, PAGE_SIZE};
+use heapless::{FnvIndexMap, Vec};
+
+const MAGIC: u32 = 0x5345_5454;
+/// Magic word of a retired page, programmable over [`MAGIC`].
+const RETIRED: u32 = 0;
+const PAGE_HEADER_LEN: u32 = 8;
+const RECORD_HEADER_LEN: u32 = 8;
+const ERASED: u32 = 0xffff_ffff;
+/// Length marking a removed key.
+const REMOVED: u16 = 0xffff;
+/// Keeps a record header from reading as erased flash.
+const RESERVED_KEY: u16 = 0xffff;
+
+/// Longest value (in bytes).
+pub const MAX_VALUE_LEN: usize = (PAGE_SIZE - PAGE_HEADER_LEN - RECORD_HEADER_LEN) as usize;
+
+/// Storage errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The flash driver failed, e.g. on a locked page.
+    Flash(flash::Error),
+    /// The reserved pages are fewer than two, not page aligned or outside
+    /// the main flash.
+    InvalidRegion,
+    /// Key 0xFFFF is reserved.
+    InvalidKey,
+    /// The value is longer than [`MAX_VALUE_LEN`], or a result does not fit
+    /// its buffer.
+    TooLarge,
+    /// The live records do not leave room for the new one in a page.
+    Full,
+    /// The index already holds `KEYS` keys.
+    TooManyKeys,
+}
+
+impl From<flash::Error> for Error {
+    fn from(error: flash::Error) -> Self {
+        Error::Flash(error)
+    }
+}
+
+/// Record found in a page.
+#[derive(Clone, Copy)]
+struct Record {
+    addr: u32,
+    key: u16,
+    len: u16,
+}
+
+impl Record {
+    fn header(&self) -> u32 {
+        u32::from(self.key) << 16 | u32::from(self.len)
+    }
+
+    fn is_removed(&self) -> bool {
+        self.len == REMOVED
+    }
+
+    fn value_addr(&self) -> u32 {
+        self.addr + RECORD_HEADER_LEN
+    }
+
+    fn value_len(&self) -> u32 {
+        if self.is_removed() {
+            0
+        } else {
+            u32::from(self.len)
+        }
+    }
+
+    /// Address following the record.
+    fn end(&self) -> u32 {
+        self.value_addr() + padded(self.value_len())
+    }
+
+    /// Size of the record (in bytes).
+    fn size(&self) -> u32 {
+        self.end() - self.addr
+    }
+}
+
+fn padded(len: u32) -> u32 {
+    (len + 3) & !3
+}
+
+/// CRC-32 (IEEE) of `bytes`, continuing from a previous `crc` or 0.
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Settings store indexing up to `KEYS` keys, which must be a power of two.
+pub struct Storage<const KEYS: usize> {
+    flash: Flash,
+    base: u32,
+    pages: u32,
+    active: u32,
+    seq: u32,
+    next: u32,
+    index: FnvIndexMap<u16, Record, KEYS>,
+}
+
+impl<const KEYS: usize> Storage<KEYS> {
+    /// Mounts the store kept in `pages` pages from `base`, formatting them
+    /// if none of them is active. The flash driver is returned on errors.
+    pub fn new(flash: Flash, base: u32, pages: u32) -> Result<Self, (Flash, Error)> {
+        let in_flash = pages
+            .checked_mul(PAGE_SIZE)
+            .and_then(|len| base.checked_add(len))
+            .is_some_and(|end| end <= FLASH_BASE + FLASH_SIZE);
+        if pages < 2 || !base.is_multiple_of(PAGE_SIZE) || !in_flash {
+            return Err((flash, Error::InvalidRegion));
+        }
+        let mut storage = Self {
+            flash,
+            base,
+            pages,
+            active: 0,
+            seq: 0,
+            next: 0,
+            index: FnvIndexMap::new(),
+        };
+        match storage.mount() {
+            Ok(()) => Ok(storage),
+            Err(error) => Err((storage.flash, error)),
+        }
+    }
+
+    /// Releases the flash driver.
+    pub fn free(self) -> Flash {
+        self.flash
+    }
+
+    /// Reads the value of `key`, or `None` if it is not set.
+    pub fn get<const N: usize>(&self, key: u16) -> Result<Option<Vec<u8, N>>, Error> {
+        let record = match self.index.get(&key) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let mut value = Vec::new();
+        value
+            .resize(record.value_len() as usize, 0)
+            .map_err(|_| Error::TooLarge)?;
+        self.flash.read(record.value_addr(), &mut value)?;
+        Ok(Some(value))
+    }
+
+    /// Checks if `key` is set.
+    pub fn contains(&self, key: u16) -> bool {
+        self.index.contains_key(&key)
+    }
+
+    /// Lists the keys that are set.
+    pub fn keys<const N: usize>(&self) -> Result<Vec<u16, N>, Error> {
+        let mut keys = Vec::new();
+        for &key in self.index.keys() {
+            keys.push(key).map_err(|_| Error::TooLarge)?;
+        }
+        Ok(keys)
+    }
+
+    /// Sets `key` to `value`, compacting the log if the active page is full.
+    pub fn set(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        if key == RESERVED_KEY {
+            return Err(Error::InvalidKey);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::TooLarge);
+        }
+        if !self.contains(key) && self.index.len() == KEYS {
+            return Err(Error::TooManyKeys);
+        }
+        self.append(key, value.len() as u16, value)
+    }
+
+    /// Removes `key`, if it is set.
+    pub fn remove(&mut self, key: u16) -> Result<(), Error> {
+        if key == RESERVED_KEY {
+            return Err(Error::InvalidKey);
+        }
+        if !self.contains(key) {
+            return Ok(());
+        }
+        self.append(key, REMOVED, &[])
+    }
+
+    fn page_addr(&self, page: u32) -> u32 {
+        self.base + page * PAGE_SIZE
+    }
+
+    fn word(&self, addr: u32) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        self.flash.read(addr, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Picks the active page with the highest sequence number, or formats
+    /// the first page, then indexes it.
+    fn mount(&mut self) -> Result<(), Error> {
+        let mut active = None;
+        for page in 0..self.pages {
+            let addr = self.page_addr(page);
+            if self.word(addr + 4)? != MAGIC {
+                continue;
+            }
+            let seq = self.word(addr)?;
+            match active {
+                Some((_, newest)) if newest >= seq => {}
+                _ => active = Some((page, seq)),
+            }
+        }
+        match active {
+            Some((page, seq)) => {
+                self.active = page;
+                self.seq = seq;
+                self.next = self.scan(page)?;
+            }
+            None => {
+                self.erase(0)?;
+                self.write_header(0, 0)?;
+                self.active = 0;
+                self.seq = 0;
+                self.next = self.page_addr(0) + PAGE_HEADER_LEN;
+            }
+        }
+        Ok(())
+    }
+
+    /// Retires `page` if it holds the magic word, so a power loss during the
+    /// erase can't leave it looking active, then erases it.
+    fn erase(&mut self, page: u32) -> Result<(), Error> {
+        self.retire(page)?;
+        self.flash.erase_page(self.page_addr(page))?;
+        Ok(())
+    }
+
+    /// Programs the magic word of `page` to [`RETIRED`] if it is active.
+    fn retire(&mut self, page: u32) -> Result<(), Error> {
+        let addr = self.page_addr(page) + 4;
+        if self.word(addr)? == MAGIC {
+            self.flash.write_u32(addr, RETIRED)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the sequence number, then the magic word activating the page.
+    fn write_header(&mut self, page: u32, seq: u32) -> Result<(), Error> {
+        let addr = self.page_addr(page);
+        self.flash.write_u32(addr, seq)?;
+        self.flash.write_u32(addr + 4, MAGIC)?;
+        Ok(())
+    }
+
+    /// Record at `addr` in `page`, or `None` at the end of the log.
+    fn record_at(&self, page: u32, addr: u32) -> Result<Option<Record>, Error> {
+        let end = self.page_addr(page) + PAGE_SIZE;
+        if addr + RECORD_HEADER_LEN > end {
+            return Ok(None);
+        }
+        let header = self.word(addr)?;
+        if header == ERASED {
+            return Ok(None);
+        }
+        let record = Record {
+            addr,
+            key: (header >> 16) as u16,
+            len: header as u16,
+        };
+        if record.end() > end {
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    /// Indexes the latest valid record of each key in `page` and returns the
+    /// address following the last record. A damaged record header ends the
+    /// log and fills the page, so nothing is written over it.
+    fn scan(&mut self, page: u32) -> Result<u32, Error> {
+        let end = self.page_addr(page) + PAGE_SIZE;
+        let mut addr = self.page_addr(page) + PAGE_HEADER_LEN;
+        while let Some(record) = self.record_at(page, addr)? {
+            if self.is_valid(&record)? {
+                self.index_record(record)?;
+            }
+            addr = record.end();
+        }
+        if addr < end && self.word(addr)? != ERASED {
+            return Ok(end);
+        }
+        Ok(addr)
+    }
+
+    /// Makes `record` the latest record of its key.
+    fn index_record(&mut self, record: Record) -> Result<(), Error> {
+        if record.is_removed() {
+            self.index.remove(&record.key);
+            return Ok(());
+        }
+        self.index
+            .insert(record.key, record)
+            .map_err(|_| Error::TooManyKeys)?;
+        Ok(())
+    }
+
+    /// Checks the record CRC.
+    fn is_valid(&self, record: &Record) -> Result<bool, Error> {
+        let mut crc = crc32(0, &record.header().to_le_bytes());
+        let mut buf = [0; 16];
+        let mut addr = record.value_addr();
+        let end = addr + record.value_len();
+        while addr < end {
+            let len = (end - addr).min(buf.len() as u32);
+            let chunk = &mut buf[..len as usize];
+            self.flash.read(addr, chunk)?;
+            crc = crc32(crc, chunk);
+            addr += len;
+        }
+        Ok(self.word(record.addr + 4)? == crc)
+    }
+
+    /// Writes the header first, claiming the space, and the CRC last,
+    /// committing the record.
+    fn append(&mut self, key: u16, len: u16, value: &[u8]) -> Result<(), Error> {
+        let size = RECORD_HEADER_LEN + padded(value.len() as u32);
+        if self.next + size > self.page_addr(self.active) + PAGE_SIZE {
+            // Compacting only helps if the new record then fits, which also
+            // keeps a caller retrying on `Full` from wearing out the pages
+            let live: u32 = self.index.values().map(Record::size).sum();
+            if PAGE_HEADER_LEN + live + size > PAGE_SIZE {
+                return Err(Error::Full);
+            }
+            self.compact()?;
+        }
+        let record = Record {
+            addr: self.next,
+            key,
+            len,
+        };
+        self.flash.write_u32(record.addr, record.header())?;
+        self.next = record.end();
+        for (addr, chunk) in (record.value_addr()..).step_by(4).zip(value.chunks(4)) {
+            let mut word = [0xff; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.flash.write_u32(addr, u32::from_le_bytes(word))?;
+        }
+        let crc = crc32(crc32(0, &record.header().to_le_bytes()), value);
+        self.flash.write_u32(record.addr + 4, crc)?;
+        self.index_record(record)
+    }
+
+    /// Copies the indexed records to the next page, then activates it and
+    /// retires the old one. Removed keys are dropped.
+    fn compact(&mut self) -> Result<(), Error> {
+        let new = (self.active + 1) % self.pages;
+        self.erase(new)?;
+        let mut next = self.page_addr(new) + PAGE_HEADER_LEN;
+        let mut index = FnvIndexMap::new();
+        for record in self.index.values() {
+            for offset in (0..record.size()).step_by(4) {
+                let word = self.word(record.addr + offset)?;
+                self.flash.write_u32(next + offset, word)?;
+            }
+            let copy = Record {
+                addr: next,
+                ..*record
+            };
+            // Same keys as the current index, so there is room
+            let _ = index.insert(copy.key, copy);
+            next += record.size();
+        }
+        self.write_header(new, self.seq + 1)?;
+        // A power loss before this leaves both pages active, the new one
+        // wins on its sequence number
+        let old = self.active;
+        self.active = new;
+        self.seq += 1;
+        self.next = next;
+        self.index = index;
+        self.retire(old)
+    }
+}
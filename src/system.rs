@@ -0,0 +1,134 @@
+//! Reset cause, system status and software resets.
+//!
+//! The MAX32660 only latches the watchdog as a reset cause (WDT0 RST_FLAG).
+//! Power-on, brown-out, external reset pin and software resets are not
+//! distinguished by the hardware and are reported as [`ResetCause::Other`].
+use crate::pac::{gcr, GCR, SIR, WDT0};
+
+/// Cause of the last reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetCause {
+    /// The watchdog timer reset the device.
+    Watchdog,
+    /// Power-on, brown-out, external reset pin or software reset.
+    Other,
+}
+
+/// System initialization status, loaded from OTP after power-up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InitStatus {
+    /// The OTP magic word was set, so the trim and configuration values are valid.
+    pub config_valid: bool,
+    /// Failing OTP address if a CRC error occurred while reading the OTP.
+    pub crc_error_addr: Option<u32>,
+}
+
+/// Runtime system status flags from GCR SYSST.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SystemStatus {
+    /// The ARM ICE (debug access) is locked.
+    pub ice_locked: bool,
+    /// A code integrity error occurred.
+    pub code_integrity_error: bool,
+    /// A system cache memory fault occurred.
+    pub cache_memory_fault: bool,
+}
+
+/// Reads the reset cause without needing the system peripheral, e.g. from
+/// the watchdog driver.
+pub(crate) fn read_reset_cause() -> ResetCause {
+    let wdt = unsafe { &*WDT0::ptr() };
+    if wdt.ctrl.read().rst_flag().bit_is_set() {
+        ResetCause::Watchdog
+    } else {
+        ResetCause::Other
+    }
+}
+
+/// Clears the latched reset cause.
+pub(crate) fn clear_reset_cause() {
+    let wdt = unsafe { &*WDT0::ptr() };
+    wdt.ctrl.modify(|_, w| w.rst_flag().clear_bit());
+}
+
+/// System Initialization Registers (SIR) and GCR reset controls.
+pub struct System {
+    sir: SIR,
+}
+
+impl System {
+    pub fn new(sir: SIR) -> Self {
+        Self { sir }
+    }
+
+    /// Releases the SIR resource.
+    pub fn free(self) -> SIR {
+        self.sir
+    }
+
+    /// GCR RSTR0 reset bits are write one to trigger, so no read-modify-write
+    /// conflicts with the GCR owned by `clocks::Clocks`.
+    fn gcr(&self) -> &gcr::RegisterBlock {
+        unsafe { &*GCR::ptr() }
+    }
+
+    /// Cause of the last reset.
+    pub fn reset_cause(&self) -> ResetCause {
+        read_reset_cause()
+    }
+
+    /// Reads and clears the reset cause, so the next boot reports it afresh.
+    pub fn take_reset_cause(&mut self) -> ResetCause {
+        let cause = read_reset_cause();
+        clear_reset_cause();
+        cause
+    }
+
+    /// System initialization status from the SIR.
+    pub fn init_status(&self) -> InitStatus {
+        let sistat = self.sir.sistat.read();
+        InitStatus {
+            config_valid: sistat.magic().bit_is_set(),
+            crc_error_addr: if sistat.crcerr().bit_is_set() {
+                Some(self.sir.erraddr.read().erraddr().bits())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Runtime system status flags.
+    pub fn status(&self) -> SystemStatus {
+        let sysst = self.gcr().sysst.read();
+        SystemStatus {
+            ice_locked: sysst.iceclock().bit_is_set(),
+            code_integrity_error: sysst.codeinterr().bit_is_set(),
+            cache_memory_fault: sysst.scmemf().bit_is_set(),
+        }
+    }
+
+    /// Resets the CPU core and all peripherals, including the watchdog timer.
+    pub fn software_reset(&mut self) -> ! {
+        self.gcr().rstr0.write(|w| w.system().reset());
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    /// Resets all peripherals and GPIO but not the CPU core or watchdog timer.
+    ///
+    /// Drivers constructed before the reset must be constructed again.
+    pub fn soft_reset(&mut self) {
+        self.gcr().rstr0.write(|w| w.srst().reset());
+        while self.gcr().rstr0.read().srst().is_busy() {}
+    }
+
+    /// Resets all peripherals. The CPU core, watchdog timer and GPIO pins are
+    /// unaffected.
+    ///
+    /// Drivers constructed before the reset must be constructed again.
+    pub fn peripheral_reset(&mut self) {
+        self.gcr().rstr0.write(|w| w.prst().reset());
+        while self.gcr().rstr0.read().prst().is_busy() {}
+    }
+}
@@ -0,0 +1,246 @@
+//! General purpose 32-bit timers (TMR0, TMR1 and TMR2).
+//!
+//! Timers count at `PCLK / prescaler`. In one-shot mode the timer stops once
+//! the count reaches the compare value, in continuous mode it reloads and
+//! keeps running, and in compare mode it runs freely. In every mode the
+//! timer interrupt flag is raised on the compare match.
+//!
+//! Periods are given in ticks and equal the compare value: the count is
+//! started from 0, and on a match in continuous mode it reloads to 1 on the
+//! following tick, so every period takes exactly the compare value ticks.
+//!
+//! PWM output and input capture on the timer I/O pins are provided by the
+//! `pwm`, `capture` and `counter` modules.
+use core::ops::Deref;
+
+use crate::clocks::{gate_peripheral, Clocks, PeripheralClock, PeripheralReset};
+use crate::pac::{tmr0, Interrupt, TMR0, TMR1, TMR2};
+use cortex_m::peripheral::NVIC;
+use embedded_hal::timer::{CountDown, Periodic};
+use void::Void;
+
+/// Timer I/O pins (alternate function 3), used for PWM output, capture and
+/// counter inputs.
+pub const TMR0_IO: u8 = 3;
+pub const TMR1_IO: u8 = 9;
+pub const TMR2_IO: u8 = 7;
+
+/// Frequency in Hz.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hertz(pub u32);
+
+impl From<u32> for Hertz {
+    fn from(hz: u32) -> Self {
+        Hertz(hz)
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// TMR peripheral instances.
+pub trait Instance:
+    Deref<Target = tmr0::RegisterBlock> + PeripheralClock + PeripheralReset + sealed::Sealed
+{
+    /// NVIC interrupt raised by this timer.
+    const INTERRUPT: Interrupt;
+}
+
+macro_rules! timers {
+    ([$(($TMR: ident),)+]) => {
+        $(
+            impl sealed::Sealed for $TMR {}
+            impl Instance for $TMR {
+                const INTERRUPT: Interrupt = Interrupt::$TMR;
+            }
+        )+
+    };
+}
+
+timers!([(TMR0), (TMR1), (TMR2),]);
+
+/// Timer count clock prescaler, dividing PCLK.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prescaler {
+    D1,
+    D2,
+    D4,
+    D8,
+    D16,
+    D32,
+    D64,
+    D128,
+    D256,
+    D512,
+    D1024,
+    D2048,
+    D4096,
+}
+
+impl Prescaler {
+    /// Divider as a power of two, which is also the 4-bit PRES3:PRES value.
+    pub(crate) fn shift(self) -> u8 {
+        self as u8
+    }
+
+    /// Writes the prescaler into a timer control register.
+    pub(crate) fn write(self, tmr: &tmr0::RegisterBlock) {
+        let pres = self.shift();
+        tmr.cn
+            .modify(|_, w| w.pres().bits(pres & 0x07).pres3().bit(pres & 0x08 != 0));
+    }
+}
+
+/// Timer driver, also usable as an `embedded_hal` periodic `CountDown`.
+pub struct Timer<TMR> {
+    tmr: TMR,
+    pclk: u32,
+    psc: Prescaler,
+}
+
+impl<TMR: Instance> Timer<TMR> {
+    /// Un-gates and resets the timer, leaving it stopped.
+    pub fn new(tmr: TMR, clocks: &Clocks) -> Self {
+        clocks.enable_peripheral::<TMR>();
+        clocks.reset_peripheral::<TMR>();
+        let mut timer = Timer {
+            tmr,
+            pclk: clocks.pclk(),
+            psc: Prescaler::D1,
+        };
+        timer.set_prescaler(Prescaler::D1);
+        timer
+    }
+
+    /// Follows a change of the peripheral clock, e.g. after `Clocks::set_ovr`.
+    pub fn update_clocks(&mut self, clocks: &Clocks) {
+        self.pclk = clocks.pclk();
+    }
+
+    /// Stops the timer and releases the timer resource, gating its clock.
+    pub fn free(mut self) -> TMR {
+        self.stop();
+        self.unlisten();
+        gate_peripheral::<TMR>();
+        self.tmr
+    }
+
+    /// Selects the count clock prescaler. Takes effect on the next start.
+    pub fn set_prescaler(&mut self, psc: Prescaler) {
+        self.psc = psc;
+        psc.write(&self.tmr);
+    }
+
+    /// Frequency the timer counts at (in Hz).
+    pub fn tick_freq(&self) -> u32 {
+        self.pclk >> self.psc.shift()
+    }
+
+    /// Number of ticks in one period of `freq`, at least one.
+    pub fn ticks_for(&self, freq: Hertz) -> u32 {
+        (self.tick_freq() / freq.0.max(1)).max(1)
+    }
+
+    /// Starts the timer, stopping after `ticks` counts.
+    pub fn start_one_shot(&mut self, ticks: u32) {
+        self.stop();
+        self.tmr.cn.modify(|_, w| w.tmode().one_shot());
+        self.load(ticks.max(1));
+        self.resume();
+    }
+
+    /// Starts the timer, raising the interrupt flag every `ticks` counts.
+    pub fn start_continuous(&mut self, ticks: u32) {
+        self.stop();
+        self.tmr.cn.modify(|_, w| w.tmode().continuous());
+        self.load(ticks.max(1));
+        self.resume();
+    }
+
+    /// Starts the timer free running, raising the interrupt flag each time the
+    /// count passes `cmp`. The count wraps at 2^32.
+    pub fn start_compare(&mut self, cmp: u32) {
+        self.stop();
+        self.tmr.cn.modify(|_, w| w.tmode().compare());
+        self.load(cmp);
+        self.resume();
+    }
+
+    /// Starts the count from 0, so the first period is `cmp` ticks like the
+    /// ones following a reload to 1.
+    fn load(&mut self, cmp: u32) {
+        self.tmr.cnt.write(|w| unsafe { w.bits(0) });
+        self.tmr.cmp.write(|w| unsafe { w.bits(cmp) });
+        self.clear_interrupt();
+    }
+
+    /// Stops the timer, keeping the current count.
+    pub fn stop(&mut self) {
+        self.tmr.cn.modify(|_, w| w.ten().dis());
+    }
+
+    /// Restarts a stopped timer from the current count.
+    pub fn resume(&mut self) {
+        self.tmr.cn.modify(|_, w| w.ten().en());
+    }
+
+    /// Checks if the timer is counting.
+    pub fn is_running(&self) -> bool {
+        self.tmr.cn.read().ten().is_en()
+    }
+
+    /// Current timer count.
+    pub fn count(&self) -> u32 {
+        self.tmr.cnt.read().bits()
+    }
+
+    /// Changes the compare value of a running timer.
+    pub fn set_compare(&mut self, cmp: u32) {
+        self.tmr.cmp.write(|w| unsafe { w.bits(cmp) });
+    }
+
+    /// Enables the compare match interrupt in the NVIC.
+    pub fn listen(&mut self) {
+        unsafe { NVIC::unmask(TMR::INTERRUPT) };
+    }
+
+    /// Disables the compare match interrupt in the NVIC.
+    pub fn unlisten(&mut self) {
+        NVIC::mask(TMR::INTERRUPT);
+    }
+
+    /// Check if the compare match interrupt flag is set.
+    pub fn pending_interrupt(&self) -> bool {
+        self.tmr.intr.read().irq_clr().bit_is_set()
+    }
+
+    /// Clear the compare match interrupt flag.
+    pub fn clear_interrupt(&mut self) {
+        self.tmr.intr.write(|w| w.irq_clr().set_bit());
+    }
+}
+
+impl<TMR: Instance> CountDown for Timer<TMR> {
+    type Time = Hertz;
+
+    /// Starts the timer in continuous mode with a timeout frequency of `count`.
+    fn start<T>(&mut self, count: T)
+    where
+        T: Into<Hertz>,
+    {
+        let ticks = self.ticks_for(count.into());
+        self.start_continuous(ticks);
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        if self.pending_interrupt() {
+            self.clear_interrupt();
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl<TMR: Instance> Periodic for Timer<TMR> {}
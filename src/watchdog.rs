@@ -0,0 +1,237 @@
+//! Watchdog timer (WDT0) implementing the embedded_hal watchdog traits.
+//!
+//! The watchdog counts PCLK cycles. Once the count passes the interrupt
+//! period the early-warning interrupt flag is raised, and once it passes the
+//! reset period the device is reset. Feeding restarts the count.
+//!
+//! The WDT0 has no hardware window, so the window is kept in software: with
+//! [`Watchdog::set_window`] enabled the interrupt period also opens the
+//! window, and a feed before it forces a watchdog reset. Use
+//! [`Watchdog::caused_reset`] after boot to find out if the last reset came
+//! from the watchdog.
+//!
+//! The early-warning interrupt flag stays set until it's cleared, so a
+//! handler that does not feed must call [`Watchdog::clear_interrupt`] to
+//! avoid re-entering until the reset. Clearing the flag latches the window
+//! as open in the driver, so the next feed is still accepted.
+use crate::clocks::Clocks;
+use crate::pac::{Interrupt, WDT0};
+use crate::system::{clear_reset_cause, read_reset_cause, ResetCause};
+use cortex_m::peripheral::NVIC;
+use embedded_hal::watchdog;
+
+/// Watchdog periods, in PCLK cycles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Period {
+    Pow16 = 16,
+    Pow17,
+    Pow18,
+    Pow19,
+    Pow20,
+    Pow21,
+    Pow22,
+    Pow23,
+    Pow24,
+    Pow25,
+    Pow26,
+    Pow27,
+    Pow28,
+    Pow29,
+    Pow30,
+    Pow31,
+}
+
+impl Period {
+    /// Period as a power of two.
+    fn exp(self) -> u8 {
+        self as u8
+    }
+
+    fn from_exp(exp: u8) -> Self {
+        use Period::*;
+        [
+            Pow16, Pow17, Pow18, Pow19, Pow20, Pow21, Pow22, Pow23, Pow24, Pow25, Pow26, Pow27,
+            Pow28, Pow29, Pow30, Pow31,
+        ][usize::from(exp.clamp(16, 31) - 16)]
+    }
+
+    /// INT_PERIOD and RST_PERIOD field value, 0 for the longest period.
+    fn bits(self) -> u8 {
+        31 - self.exp()
+    }
+
+    /// The next shorter period, or the shortest one.
+    fn shorter(self) -> Self {
+        Self::from_exp(self.exp().saturating_sub(1))
+    }
+}
+
+pub struct Watchdog {
+    wdt: WDT0,
+    pclk: u32,
+    window: bool,
+    window_open: bool,
+}
+
+impl Watchdog {
+    /// Takes the watchdog, leaving it in its current state. The reset flag
+    /// is kept so [`Watchdog::caused_reset`] still reports it.
+    pub fn new(wdt: WDT0, clocks: &Clocks) -> Self {
+        Self {
+            wdt,
+            pclk: clocks.pclk(),
+            window: false,
+            window_open: false,
+        }
+    }
+
+    /// Follows a change of the peripheral clock, e.g. after `Clocks::set_ovr`.
+    pub fn update_clocks(&mut self, clocks: &Clocks) {
+        self.pclk = clocks.pclk();
+    }
+
+    /// Releases the watchdog resource, leaving it running if it was started.
+    pub fn free(self) -> WDT0 {
+        self.wdt
+    }
+
+    /// Checks if the last reset came from the watchdog.
+    pub fn caused_reset(&self) -> bool {
+        read_reset_cause() == ResetCause::Watchdog
+    }
+
+    /// Clears the watchdog reset flag, so the next boot reports it afresh.
+    pub fn clear_reset_flag(&mut self) {
+        clear_reset_cause();
+    }
+
+    /// Length of a period (in ms).
+    pub fn period_millis(&self, period: Period) -> u32 {
+        ((1u64 << period.exp()) * 1000 / u64::from(self.pclk)) as u32
+    }
+
+    /// Shortest period of at least `ms`, or the longest period.
+    pub fn period_for_millis(&self, ms: u32) -> Period {
+        let cycles = u64::from(ms) * u64::from(self.pclk) / 1000;
+        let exp = 64 - cycles.saturating_sub(1).leading_zeros();
+        Period::from_exp(exp as u8)
+    }
+
+    /// Selects the early-warning interrupt period, which should be shorter
+    /// than the reset period.
+    pub fn set_interrupt_period(&mut self, period: Period) {
+        self.wdt
+            .ctrl
+            .modify(|_, w| w.int_period().bits(period.bits()));
+    }
+
+    /// Selects the reset period.
+    pub fn set_reset_period(&mut self, period: Period) {
+        self.wdt
+            .ctrl
+            .modify(|_, w| w.rst_period().bits(period.bits()));
+    }
+
+    /// Enables the software window: feeds are only accepted once the
+    /// interrupt period has passed, an earlier feed resets the device.
+    pub fn set_window(&mut self, enable: bool) {
+        self.window = enable;
+    }
+
+    /// Enables the early-warning interrupt, also in the NVIC.
+    pub fn listen(&mut self) {
+        self.wdt.ctrl.modify(|_, w| w.int_en().en());
+        unsafe { NVIC::unmask(Interrupt::WDT0) };
+    }
+
+    /// Disables the early-warning interrupt.
+    pub fn unlisten(&mut self) {
+        NVIC::mask(Interrupt::WDT0);
+        self.wdt.ctrl.modify(|_, w| w.int_en().dis());
+    }
+
+    /// Checks if the early-warning interrupt flag is set.
+    pub fn is_pending(&self) -> bool {
+        self.wdt.ctrl.read().int_flag().is_pending()
+    }
+
+    /// Clears the early-warning interrupt flag, e.g. from its handler. The
+    /// window stays open until the next feed.
+    pub fn clear_interrupt(&mut self) {
+        if self.is_pending() {
+            self.window_open = true;
+        }
+        self.wdt.ctrl.modify(|_, w| w.int_flag().inactive());
+    }
+
+    /// Checks if the interrupt period has passed since the last feed, so a
+    /// feed is accepted with the window enabled.
+    pub fn is_window_open(&self) -> bool {
+        self.window_open || self.is_pending()
+    }
+
+    /// Checks if the watchdog is running.
+    pub fn is_enabled(&self) -> bool {
+        self.wdt.ctrl.read().wdt_en().is_en()
+    }
+
+    /// Restarts the count and clears the interrupt flag.
+    fn restart(&mut self) {
+        self.wdt.rst.write(|w| w.wdt_rst().seq0());
+        self.wdt.rst.write(|w| w.wdt_rst().seq1());
+        self.wdt.ctrl.modify(|_, w| w.int_flag().inactive());
+        self.window_open = false;
+    }
+
+    /// Resets the device through the watchdog, so the reset is reported as
+    /// a watchdog reset.
+    fn force_reset(&mut self) -> ! {
+        // Deliberate immediate reset: the early-warning handler must not get
+        // a chance to feed or clear the flag while the reset is pending.
+        NVIC::mask(Interrupt::WDT0);
+        self.wdt.ctrl.modify(|_, w| {
+            w.rst_period().bits(Period::Pow16.bits()).rst_en().en().wdt_en().en()
+        });
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+}
+
+impl watchdog::Watchdog for Watchdog {
+    /// Restarts the count. With the window enabled a feed before the
+    /// interrupt period resets the device.
+    fn feed(&mut self) {
+        if self.window && !self.is_window_open() {
+            self.force_reset();
+        }
+        self.restart();
+    }
+}
+
+impl watchdog::WatchdogEnable for Watchdog {
+    type Time = Period;
+
+    /// Starts the watchdog with a reset period of `period`. The interrupt
+    /// period is shortened to half of it if it was not already shorter.
+    fn start<T>(&mut self, period: T)
+    where
+        T: Into<Period>,
+    {
+        let period = period.into();
+        // Field values grow as periods shrink
+        let int_bits = self.wdt.ctrl.read().int_period().bits();
+        if int_bits <= period.bits() {
+            self.set_interrupt_period(period.shorter());
+        }
+        self.set_reset_period(period);
+        self.restart();
+        self.wdt.ctrl.modify(|_, w| w.rst_en().en().wdt_en().en());
+    }
+}
+
+impl watchdog::WatchdogDisable for Watchdog {
+    fn disable(&mut self) {
+        self.wdt.ctrl.modify(|_, w| w.wdt_en().dis().rst_en().dis());
+    }
+}